@@ -0,0 +1,272 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    server::ServerLogLevel,
+    settings::{Settings, is_default},
+    solution::SolutionSelection,
+};
+
+pub const WORKSPACE_CONFIG_FILE: &str = ".csharp-language-server.toml";
+
+pub const DEFAULT_MAX_SERVER_RESTARTS: u32 = 3;
+
+/// Options for the wrapper, read from configuration files and the command line.
+///
+/// Every field is optional, so configurations can be layered on top of each other.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Solution (.sln) path, relative to the workspace root
+    pub solution: Option<String>,
+    /// Project (.csproj) paths. The solution takes precedence
+    pub projects: Option<Vec<String>>,
+    pub solution_selection: Option<SolutionSelection>,
+    pub preferred_solution: Option<String>,
+    /// Version of Microsoft.CodeAnalysis.LanguageServer, or `latest` for the newest on the feeds
+    pub server_version: Option<String>,
+    /// Directory to download and execute Microsoft.CodeAnalysis.LanguageServer from
+    pub server_directory: Option<PathBuf>,
+    pub remove_old_server_versions: Option<bool>,
+    /// NuGet v3 service indexes to download Microsoft.CodeAnalysis.LanguageServer from
+    pub server_feeds: Option<Vec<String>>,
+    pub server_log_level: Option<ServerLogLevel>,
+    /// Extra arguments passed to Microsoft.CodeAnalysis.LanguageServer
+    pub server_args: Option<Vec<String>>,
+    /// Environment variables set for Microsoft.CodeAnalysis.LanguageServer
+    pub server_env: Option<BTreeMap<String, String>>,
+    /// Load the Razor extension into the server, for `.razor` and `.cshtml` documents
+    pub razor: Option<bool>,
+    /// Version of Microsoft.VisualStudioCode.RazorExtension matching the server, or `latest` for the
+    /// newest on the feeds
+    pub razor_version: Option<String>,
+    /// How often the server is restarted in a row after exiting unexpectedly. 0 disables restarts
+    pub max_server_restarts: Option<u32>,
+    pub features: Features,
+    /// Settings the server asks for with `workspace/configuration`
+    #[serde(skip_serializing_if = "is_default")]
+    pub settings: Settings,
+}
+
+/// Workarounds that can be turned off. All are enabled unless disabled.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Features {
+    pub restore: Option<bool>,
+    pub diagnostic_refresh: Option<bool>,
+    pub push_diagnostics: Option<bool>,
+    pub workspace_configuration: Option<bool>,
+    pub source_generated_documents: Option<bool>,
+    pub metadata_as_source: Option<bool>,
+}
+
+impl Features {
+    pub fn restore(&self) -> bool {
+        self.restore.unwrap_or(true)
+    }
+
+    pub fn diagnostic_refresh(&self) -> bool {
+        self.diagnostic_refresh.unwrap_or(true)
+    }
+
+    pub fn push_diagnostics(&self) -> bool {
+        self.push_diagnostics.unwrap_or(true)
+    }
+
+    pub fn workspace_configuration(&self) -> bool {
+        self.workspace_configuration.unwrap_or(true)
+    }
+
+    pub fn source_generated_documents(&self) -> bool {
+        self.source_generated_documents.unwrap_or(true)
+    }
+
+    pub fn metadata_as_source(&self) -> bool {
+        self.metadata_as_source.unwrap_or(true)
+    }
+
+    fn merge(self, other: Features) -> Features {
+        Features {
+            restore: other.restore.or(self.restore),
+            diagnostic_refresh: other.diagnostic_refresh.or(self.diagnostic_refresh),
+            push_diagnostics: other.push_diagnostics.or(self.push_diagnostics),
+            workspace_configuration: other
+                .workspace_configuration
+                .or(self.workspace_configuration),
+            source_generated_documents: other
+                .source_generated_documents
+                .or(self.source_generated_documents),
+            metadata_as_source: other.metadata_as_source.or(self.metadata_as_source),
+        }
+    }
+}
+
+impl Config {
+    /// Layers the user configuration, the workspace configuration and the given command line
+    /// options, in increasing order of precedence.
+    pub fn load(command_line: Config, workspace_dir: &Path) -> Result<Config> {
+        Config::load_with_user_config(command_line, user_config_path().as_deref(), workspace_dir)
+    }
+
+    /// Loads like [`Config::load`], with the user configuration read from `user_config`.
+    pub fn load_with_user_config(
+        command_line: Config,
+        user_config: Option<&Path>,
+        workspace_dir: &Path,
+    ) -> Result<Config> {
+        let mut config = Config::default();
+
+        if let Some(path) = user_config
+            && path.exists()
+        {
+            config = config.merge(Config::from_file(path)?);
+        }
+
+        if let Some(path) = find_workspace_config(workspace_dir) {
+            config = config.merge(Config::from_file(&path)?);
+        }
+
+        Ok(config.merge(command_line))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read configuration {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid configuration {}", path.display()))
+    }
+
+    /// Returns a configuration where every option set in `other` replaces the one in `self`.
+    pub fn merge(self, other: Config) -> Config {
+        Config {
+            solution: other.solution.or(self.solution),
+            projects: other.projects.or(self.projects),
+            solution_selection: other.solution_selection.or(self.solution_selection),
+            preferred_solution: other.preferred_solution.or(self.preferred_solution),
+            server_version: other.server_version.or(self.server_version),
+            server_directory: other.server_directory.or(self.server_directory),
+            remove_old_server_versions: other
+                .remove_old_server_versions
+                .or(self.remove_old_server_versions),
+            server_feeds: other.server_feeds.or(self.server_feeds),
+            server_log_level: other.server_log_level.or(self.server_log_level),
+            server_args: other.server_args.or(self.server_args),
+            server_env: other.server_env.or(self.server_env),
+            razor: other.razor.or(self.razor),
+            razor_version: other.razor_version.or(self.razor_version),
+            max_server_restarts: other.max_server_restarts.or(self.max_server_restarts),
+            features: self.features.merge(other.features),
+            settings: self.settings.merge(other.settings),
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("Unable to serialize configuration")
+    }
+}
+
+/// `config.toml` in the user configuration directory.
+pub fn user_config_path() -> Option<PathBuf> {
+    ProjectDirs::from("com", "github", "csharp-language-server")
+        .map(|dirs| dirs.config_dir().join("config.toml"))
+}
+
+/// The closest workspace configuration file in the directory or any of its parents.
+pub fn find_workspace_config(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(WORKSPACE_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn parses_configuration() {
+        let config: Config = toml::from_str(
+            r#"
+            solution = "src/App.sln"
+            solution-selection = "document"
+            server-version = "5.0.0-1.25277.114"
+            server-args = ["--razorSourceGenerator=foo"]
+            server-log-level = "trace"
+            server-env = { DOTNET_ROOT = "/opt/dotnet" }
+
+            [features]
+            push-diagnostics = false
+
+            [settings.inlay_hints]
+            csharp_enable_inlay_hints_for_types = true
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.solution.as_deref(), Some("src/App.sln"));
+        assert_eq!(config.solution_selection, Some(SolutionSelection::Document));
+        assert_eq!(config.server_version.as_deref(), Some("5.0.0-1.25277.114"));
+        assert_eq!(config.server_log_level, Some(ServerLogLevel::Trace));
+        assert_eq!(
+            config.server_env.unwrap()["DOTNET_ROOT"],
+            "/opt/dotnet".to_string()
+        );
+        assert!(!config.features.push_diagnostics());
+        assert!(config.features.restore());
+        assert_eq!(
+            config
+                .settings
+                .inlay_hints
+                .csharp_enable_inlay_hints_for_types,
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("solutions = \"App.sln\"").is_err());
+    }
+
+    #[test]
+    fn later_configuration_wins() {
+        let file = Config {
+            solution: Some("File.sln".to_string()),
+            server_version: Some("1.0.0".to_string()),
+            features: Features {
+                restore: Some(false),
+                ..Features::default()
+            },
+            ..Config::default()
+        };
+        let command_line = Config {
+            solution: Some("Cli.sln".to_string()),
+            ..Config::default()
+        };
+
+        let config = file.merge(command_line);
+
+        assert_eq!(config.solution.as_deref(), Some("Cli.sln"));
+        assert_eq!(config.server_version.as_deref(), Some("1.0.0"));
+        assert!(!config.features.restore());
+    }
+
+    #[test]
+    fn finds_workspace_configuration_in_parent() {
+        let tmp = TempDir::new().unwrap();
+        let nested = tmp.path().join("src").join("App");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(tmp.path().join(WORKSPACE_CONFIG_FILE), "").unwrap();
+
+        assert_eq!(
+            find_workspace_config(&nested),
+            Some(tmp.path().join(WORKSPACE_CONFIG_FILE))
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    env,
+    ffi::OsString,
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::{
+    config::Config,
+    manifest::Manifest,
+    path::{find_projects, find_solutions},
+    server::{
+        ServerOptions, current_rid, installed_server_path, installed_versions, log_dir,
+        server_root_dir,
+    },
+    solution::{SolutionMemory, SolutionSelection, preselect},
+};
+
+/// The outcome of the `doctor` checks.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub summary: String,
+    pub details: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl Check {
+    fn new(name: &str, status: Status, summary: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            summary: summary.into(),
+            details: vec![],
+        }
+    }
+
+    fn details(mut self, details: impl IntoIterator<Item = String>) -> Self {
+        self.details.extend(details);
+        self
+    }
+}
+
+impl Report {
+    pub fn has_errors(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.status == Status::Error)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warning => "warning",
+                Status::Error => "error",
+            };
+            writeln!(
+                f,
+                "{:<9} {}: {}",
+                format!("[{status}]"),
+                check.name,
+                check.summary
+            )?;
+            for detail in &check.details {
+                writeln!(f, "{:<9}   {detail}", "")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks what the server needs to start and open the workspace.
+pub async fn diagnose(options: &ServerOptions, config: &Config, workspace_dir: &Path) -> Report {
+    let dotnet = find_on_path("dotnet", env::var_os("PATH"));
+    let mut checks = vec![check_dotnet(dotnet.as_deref())];
+    if let Some(dotnet) = &dotnet {
+        checks.push(check_listed(dotnet, "runtimes", "--list-runtimes").await);
+        checks.push(check_listed(dotnet, "sdks", "--list-sdks").await);
+    }
+    checks.push(check_server(options));
+    checks.push(check_solution(
+        config,
+        workspace_dir,
+        &SolutionMemory::default(),
+    ));
+    checks.push(check_directory(
+        "cache directory",
+        &server_root_dir(options),
+    ));
+    checks.push(check_directory("log directory", &log_dir()));
+    Report { checks }
+}
+
+fn check_dotnet(dotnet: Option<&Path>) -> Check {
+    match dotnet {
+        Some(path) => Check::new("dotnet", Status::Ok, path.display().to_string()),
+        None => Check::new(
+            "dotnet",
+            Status::Error,
+            "dotnet is not on PATH. The .NET SDK is needed to load projects",
+        ),
+    }
+}
+
+/// Runs `dotnet --list-runtimes` or `dotnet --list-sdks`, which print one line per installation.
+async fn check_listed(dotnet: &Path, name: &str, flag: &str) -> Check {
+    let output = match Command::new(dotnet).arg(flag).output().await {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            return Check::new(
+                name,
+                Status::Error,
+                format!("dotnet {flag} failed with {}", output.status),
+            )
+            .details(lines(&output.stderr));
+        }
+        Err(e) => {
+            return Check::new(name, Status::Error, format!("Unable to run dotnet: {e}"));
+        }
+    };
+
+    let installed = lines(&output.stdout);
+    if installed.is_empty() {
+        Check::new(name, Status::Warning, format!("No {name} are installed"))
+    } else {
+        Check::new(name, Status::Ok, format!("{} installed", installed.len())).details(installed)
+    }
+}
+
+fn lines(output: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn check_server(options: &ServerOptions) -> Check {
+    let root_dir = server_root_dir(options);
+    let rid = current_rid();
+    let other_platforms: Vec<String> = installed_versions(&root_dir)
+        .into_iter()
+        .filter(|version| !root_dir.join(version).join(rid).exists())
+        .map(|version| format!("{version} is installed for another platform than {rid}"))
+        .collect();
+
+    let check = match installed_server_path(options) {
+        Ok(path) => Check::new(
+            "server",
+            Status::Ok,
+            format!("{} for {rid} at {}", options.version, path.display()),
+        ),
+        Err(_) if !root_dir.join(&options.version).exists() => Check::new(
+            "server",
+            Status::Warning,
+            format!(
+                "{} for {rid} is not installed, and is installed at the first launch",
+                options.version
+            ),
+        ),
+        Err(_)
+            if root_dir.join(&options.version).join(rid).exists()
+                && !Manifest::exists(&root_dir.join(&options.version)) =>
+        {
+            Check::new(
+                "server",
+                Status::Warning,
+                format!(
+                    "{} for {rid} has no manifest, which is recorded at the first launch",
+                    options.version
+                ),
+            )
+        }
+        Err(e) => Check::new(
+            "server",
+            Status::Error,
+            format!(
+                "{} for {rid} is damaged, and is installed again at the first launch: {e:#}",
+                options.version
+            ),
+        ),
+    };
+    check.details(other_platforms)
+}
+
+/// The solution or projects opened for the workspace, as far as they can be known without the
+/// editor.
+fn check_solution(config: &Config, workspace_dir: &Path, memory: &SolutionMemory) -> Check {
+    let name = "solution";
+    if let Some(solution) = &config.solution {
+        let path = workspace_dir.join(solution.trim());
+        return if path.is_file() {
+            Check::new(name, Status::Ok, format!("{} (configured)", path.display()))
+        } else {
+            Check::new(
+                name,
+                Status::Error,
+                format!("The configured solution {} does not exist", path.display()),
+            )
+        };
+    }
+
+    let candidates = find_solutions(workspace_dir);
+    let found = candidates
+        .iter()
+        .map(|candidate| format!("Found {}", candidate.display()));
+    if candidates.is_empty() {
+        let projects = config
+            .projects
+            .clone()
+            .unwrap_or_else(|| display_all(&find_projects(workspace_dir)));
+        if projects.is_empty() {
+            return Check::new(
+                name,
+                Status::Warning,
+                format!(
+                    "No solution or project found in {}",
+                    workspace_dir.display()
+                ),
+            );
+        }
+        return Check::new(
+            name,
+            Status::Ok,
+            format!("No solution found, opening {} project(s)", projects.len()),
+        )
+        .details(projects);
+    }
+
+    let preferred = config.preferred_solution.as_deref();
+    let summary = match preselect(&candidates, preferred, memory, workspace_dir) {
+        Some(solution) => solution.display().to_string(),
+        None => match config.solution_selection.unwrap_or_default() {
+            SolutionSelection::First => format!("{} (first)", candidates[0].display()),
+            SolutionSelection::Document => {
+                "The solution containing the first opened document".to_string()
+            }
+            SolutionSelection::Ask => "Asked when the editor starts".to_string(),
+        },
+    };
+    Check::new(name, Status::Ok, summary).details(found)
+}
+
+fn display_all(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect()
+}
+
+/// Checks that files can be created in the directory.
+fn check_directory(name: &str, dir: &Path) -> Check {
+    let probe = dir.join(format!(".doctor-{}", std::process::id()));
+    let writable = fs::create_dir_all(dir)
+        .and_then(|()| fs::write(&probe, ""))
+        .and_then(|()| fs::remove_file(&probe));
+    match writable {
+        Ok(()) => Check::new(name, Status::Ok, dir.display().to_string()),
+        Err(e) => Check::new(
+            name,
+            Status::Error,
+            format!("{} is not writable: {e}", dir.display()),
+        ),
+    }
+}
+
+/// The program in the directories of a `PATH` value.
+fn find_on_path(program: &str, path: Option<OsString>) -> Option<PathBuf> {
+    let names = if cfg!(windows) {
+        vec![format!("{program}.exe"), program.to_string()]
+    } else {
+        vec![program.to_string()]
+    };
+    env::split_paths(&path?)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn finds_program_on_path() {
+        let tmp = TempDir::new().unwrap();
+        let bin = tmp.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        let name = if cfg!(windows) {
+            "dotnet.exe"
+        } else {
+            "dotnet"
+        };
+        fs::write(bin.join(name), "").unwrap();
+        let path = env::join_paths([tmp.path().to_path_buf(), bin.clone()]).unwrap();
+
+        assert_eq!(find_on_path("dotnet", Some(path)), Some(bin.join(name)));
+        assert_eq!(find_on_path("dotnet", None), None);
+    }
+
+    #[test]
+    fn reports_solution_to_open() {
+        let tmp = TempDir::new().unwrap();
+        let memory = SolutionMemory::at(tmp.path().join("solutions.json"));
+        let workspace = tmp.path().join("repo");
+        fs::create_dir_all(workspace.join("src")).unwrap();
+        fs::write(workspace.join("src").join("App.csproj"), "").unwrap();
+
+        let check = check_solution(&Config::default(), &workspace, &memory);
+        assert_eq!(check.status, Status::Ok);
+        assert_eq!(
+            check.details,
+            vec![
+                workspace
+                    .join("src")
+                    .join("App.csproj")
+                    .display()
+                    .to_string()
+            ]
+        );
+
+        fs::write(workspace.join("B.sln"), "").unwrap();
+        fs::write(workspace.join("src").join("A.sln"), "").unwrap();
+        let check = check_solution(&Config::default(), &workspace, &memory);
+        assert_eq!(
+            check.summary,
+            format!("{} (first)", workspace.join("B.sln").display())
+        );
+        assert_eq!(check.details.len(), 2);
+
+        let config = Config {
+            solution: Some("Missing.sln".to_string()),
+            ..Config::default()
+        };
+        let check = check_solution(&config, &workspace, &memory);
+        assert_eq!(check.status, Status::Error);
+    }
+
+    #[test]
+    fn prints_readable_report() {
+        let tmp = TempDir::new().unwrap();
+        let report = Report {
+            checks: vec![
+                check_directory("cache directory", tmp.path()),
+                check_dotnet(None),
+            ],
+        };
+
+        let text = report.to_string();
+        assert!(text.starts_with(&format!(
+            "[ok]      cache directory: {}",
+            tmp.path().display()
+        )));
+        assert!(text.contains("[error]   dotnet: dotnet is not on PATH"));
+        assert!(report.has_errors());
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"][1]["status"], "error");
+    }
+}
@@ -0,0 +1,48 @@
+use std::{io, path::PathBuf};
+
+/// Errors of the wrapper.
+///
+/// Errors about a single URI or path are recovered from by skipping what they are about. The
+/// server failing to install or start is fatal, and is reported to the client.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{uri:?} is not a valid URI: {source}")]
+    InvalidUri {
+        uri: String,
+        source: url::ParseError,
+    },
+
+    #[error("{0:?} is not a file URI")]
+    NotAFileUri(String),
+
+    #[error("{} cannot be turned into a file URI", .0.display())]
+    NotAnAbsolutePath(PathBuf),
+
+    #[error("The initialize request has no workspace folders, root URI or root path")]
+    NoWorkspace,
+
+    #[error("Unable to serialize notification: {0}")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error("Unable to install the server: {0:#}")]
+    Install(anyhow::Error),
+
+    #[error(
+        "dotnet was not found. The .NET runtime is needed to run the server on this platform, install it or add it to PATH"
+    )]
+    DotnetNotFound,
+
+    #[error("Unable to run the server {}: {source}", .program.display())]
+    Spawn { program: PathBuf, source: io::Error },
+
+    #[error("{arg} is passed to the server by the wrapper. {hint}")]
+    ReservedServerArg { arg: String, hint: &'static str },
+
+    #[error("{0:?} is not a valid environment variable name")]
+    InvalidServerEnv(String),
+
+    #[error("The server input or output is not piped")]
+    NotPiped,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -1,4 +1,20 @@
+pub mod config;
+pub mod doctor;
+pub mod error;
+pub mod logging;
+pub mod manifest;
+pub mod middleware;
 pub mod notification;
+pub mod nuget;
 pub mod path;
+pub mod process;
+pub mod progress;
+pub mod proxy;
+pub mod replay;
 pub mod server;
 pub mod server_version;
+pub mod session;
+pub mod settings;
+pub mod solution;
+pub mod trace;
+pub mod transport;
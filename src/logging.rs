@@ -0,0 +1,74 @@
+use std::{env, panic, path::Path};
+
+use anyhow::{Context, Result};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{self, Rotation},
+};
+use tracing_subscriber::filter::LevelFilter;
+
+/// Environment variable setting the log level when `--log-level` is not given.
+pub const LOG_LEVEL_ENV: &str = "CSHARP_LANGUAGE_SERVER_LOG";
+
+const DEFAULT_LEVEL: &str = "info";
+const LOG_FILE_PREFIX: &str = "csharp-language-server";
+const MAX_LOG_FILES: usize = 7;
+
+/// Writes the logs of the wrapper to a daily rotating file in `dir`, keeping the last week of
+/// files. Panics are logged as errors before the default hook reports them.
+///
+/// The returned guard flushes the logs when dropped, which has to happen before the process exits.
+pub fn init(dir: &Path, level: Option<&str>) -> Result<WorkerGuard> {
+    let level = level_filter(level, env::var(LOG_LEVEL_ENV).ok().as_deref())?;
+
+    let appender = rolling::Builder::new()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix("log")
+        .max_log_files(MAX_LOG_FILES)
+        .build(dir)
+        .with_context(|| format!("Unable to write logs to {}", dir.display()))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(writer)
+        .with_ansi(false)
+        .try_init()
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        tracing::error!("{info}");
+        default_hook(info);
+    }));
+
+    Ok(guard)
+}
+
+/// The level given by flag, else by environment variable, else `info`.
+fn level_filter(flag: Option<&str>, env: Option<&str>) -> Result<LevelFilter> {
+    let level = flag
+        .or(env.filter(|env| !env.trim().is_empty()))
+        .unwrap_or(DEFAULT_LEVEL);
+    level.trim().parse().with_context(|| {
+        format!("Invalid log level {level:?}, expected off, error, warn, info, debug or trace")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_takes_precedence_over_environment() {
+        assert_eq!(level_filter(None, None).unwrap(), LevelFilter::INFO);
+        assert_eq!(level_filter(None, Some("")).unwrap(), LevelFilter::INFO);
+        assert_eq!(level_filter(None, Some("WARN")).unwrap(), LevelFilter::WARN);
+        assert_eq!(
+            level_filter(Some("debug"), Some("warn")).unwrap(),
+            LevelFilter::DEBUG
+        );
+        assert!(level_filter(Some("loud"), None).is_err());
+    }
+}
@@ -1,31 +1,83 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{collections::BTreeMap, env, io::Cursor, path::PathBuf};
 
-use clap::Parser;
-use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt, BufReader},
-    try_join,
-};
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use tokio::io::{self, AsyncBufRead, AsyncReadExt, BufReader};
+use tracing::{error, info};
 
 use csharp_language_server::{
-    path::create_open_notification,
-    server::{download_server, start_server},
-    server_version::SERVER_VERSION,
+    config::{Config, DEFAULT_MAX_SERVER_RESTARTS},
+    doctor::diagnose,
+    error::Error,
+    logging,
+    middleware::{
+        diagnostic_refresh::DiagnosticRefresh, metadata_as_source::MetadataAsSource,
+        open_workspace::OpenWorkspace, push_diagnostics::PushDiagnostics, razor::Razor,
+        restore::Restore, source_generated::SourceGenerated,
+        workspace_configuration::WorkspaceConfiguration,
+    },
+    path::workspace_folders,
+    process::{ServerProcess, termination_signal},
+    proxy::{DEFAULT_EXIT_TIMEOUT, Proxy},
+    replay::{replay_file, report},
+    server::{
+        ServerLogLevel, ServerOptions, download_server, install_server_from, log_dir, start_server,
+    },
+    solution::SolutionSelection,
+    trace::Trace,
+    transport::{MessageReader, MessageWriter},
 };
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Remove old versions of Microsoft.CodeAnalysis.LanguageServer
-    #[arg(short, long, default_value_t = true)]
-    remove_old_server_versions: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Remove old versions of Microsoft.CodeAnalysis.LanguageServer [default: true]
+    #[arg(short, long, num_args = 0..=1, default_missing_value = "true")]
+    remove_old_server_versions: Option<bool>,
 
     /// Download Microsoft.CodeAnalysis.LanguageServer. Returns path to dll (macos) or executable (win and linux)
     #[arg(long, default_value_t = false)]
     download: bool,
 
+    /// Install Microsoft.CodeAnalysis.LanguageServer from a local .nupkg or unpacked package instead of downloading it. Returns path to dll (macos) or executable (win and linux)
+    #[arg(long)]
+    install_from: Option<PathBuf>,
+
+    /// Version of Microsoft.CodeAnalysis.LanguageServer to run, or `latest` for the newest version on the feed
+    #[arg(long)]
+    server_version: Option<String>,
+
     /// Override directory to download and execute Microsoft.CodeAnalysis.LanguageServer
     #[arg(short, long)]
-    directory: Option<String>,
+    directory: Option<PathBuf>,
+
+    /// NuGet v3 service index to download Microsoft.CodeAnalysis.LanguageServer from. Can be repeated
+    #[arg(long)]
+    server_feed: Option<Vec<String>>,
+
+    /// Level of the logs Microsoft.CodeAnalysis.LanguageServer writes to the log directory [default: information]
+    #[arg(long, value_enum)]
+    server_log_level: Option<ServerLogLevel>,
+
+    /// Extra argument passed to Microsoft.CodeAnalysis.LanguageServer, e.g. --server-arg=--telemetryLevel=off. Can be repeated, and replaces server-args of the configuration
+    #[arg(long, allow_hyphen_values = true)]
+    server_arg: Option<Vec<String>>,
+
+    /// Environment variable set for Microsoft.CodeAnalysis.LanguageServer, as NAME=VALUE. Can be repeated, and replaces server-env of the configuration
+    #[arg(long, value_parser = parse_env)]
+    server_env: Option<Vec<(String, String)>>,
+
+    /// Load the Razor extension into the server, for .razor and .cshtml documents [default: false]
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    razor: Option<bool>,
+
+    /// Version of Microsoft.VisualStudioCode.RazorExtension matching the server, or `latest` for the newest version on the feed. Needed with a pinned server version
+    #[arg(long)]
+    razor_version: Option<String>,
 
     /// Override solution (.sln) path. Absolute path
     #[arg(short, long)]
@@ -34,65 +86,279 @@ struct Args {
     /// Override project(s) (.csproj) path(s). Absolute path. Solution path takes precedence
     #[arg(short, long)]
     project_paths: Option<Vec<String>>,
+
+    /// How to pick a solution when the workspace contains several [default: first]
+    #[arg(long, value_enum)]
+    solution_selection: Option<SolutionSelection>,
+
+    /// Name of the solution to open when the workspace contains several
+    #[arg(long)]
+    preferred_solution: Option<String>,
+
+    /// Level of the logs written to the log directory: off, error, warn, info, debug or trace. Overrides CSHARP_LANGUAGE_SERVER_LOG [default: info]
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Record every message between the client, the wrapper and the server to this file, as JSON lines
+    #[arg(long)]
+    trace_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Send the messages a trace file recorded for the server to a fresh server, and show how its responses differ from the recorded ones. Exits with 1 when they differ
+    Replay {
+        /// Trace file recorded with --trace-file
+        trace_file: PathBuf,
+    },
+    /// Check dotnet, the installed server, the solution to open, and the cache and log directories. Exits with 1 when a check fails
+    Doctor {
+        /// Print the report as JSON
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+}
+
+impl From<Args> for Config {
+    fn from(args: Args) -> Self {
+        Config {
+            solution: args.solution_path,
+            projects: args.project_paths,
+            solution_selection: args.solution_selection,
+            preferred_solution: args.preferred_solution,
+            server_version: args.server_version,
+            server_directory: args.directory,
+            remove_old_server_versions: args.remove_old_server_versions,
+            server_feeds: args.server_feed,
+            server_log_level: args.server_log_level,
+            server_args: args.server_arg,
+            razor: args.razor,
+            razor_version: args.razor_version,
+            server_env: args
+                .server_env
+                .map(|env| env.into_iter().collect::<BTreeMap<_, _>>()),
+            ..Config::default()
+        }
+    }
+}
+
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("{value:?} is not NAME=VALUE")),
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let version = SERVER_VERSION;
-    let directory_path = args.directory.map(|dir| PathBuf::from_str(&dir).unwrap());
 
-    if args.download {
-        let path = download_server(version, args.remove_old_server_versions, directory_path).await;
+    let log_guard = logging::init(&log_dir(), args.log_level.as_deref())
+        .inspect_err(|e| eprintln!("Logging is disabled: {e:#}"))
+        .ok();
+    info!(version = env!("CARGO_PKG_VERSION"), "Starting");
+
+    let code = match run(args).await {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{e:#}");
+            eprintln!("{e:?}");
+            1
+        }
+    };
+    info!(code, "Exiting");
+    drop(log_guard);
+    std::process::exit(code);
+}
+
+/// Runs what the command line asks for, and returns the exit code.
+async fn run(mut args: Args) -> anyhow::Result<i32> {
+    let download = args.download;
+    let install_from = args.install_from.clone();
+    let command = args.command.take();
+    let trace_file = args.trace_file.clone();
+
+    let serving = !download && install_from.is_none() && command.is_none();
+    let (client_reader, workspace_folder) = if serving {
+        let (reader, folder) = read_initialize().await?;
+        (Some(reader), folder)
+    } else {
+        (None, None)
+    };
+    let workspace_dir = match workspace_folder {
+        Some(folder) => folder,
+        None => env::current_dir().context("Unable to read current directory")?,
+    };
+    let config =
+        Config::load(args.into(), &workspace_dir).context("Unable to load configuration")?;
+    info!(workspace = %workspace_dir.display(), "Resolved configuration:\n{}", config.to_toml());
+
+    let defaults = ServerOptions::default();
+    let mut server_options = ServerOptions {
+        remove_old_server_versions: config
+            .remove_old_server_versions
+            .unwrap_or(defaults.remove_old_server_versions),
+        directory: config.server_directory.clone(),
+        feeds: config.server_feeds.clone().unwrap_or(defaults.feeds),
+        log_level: config.server_log_level.unwrap_or(defaults.log_level),
+        extra_args: config.server_args.clone().unwrap_or(defaults.extra_args),
+        env: config.server_env.clone().unwrap_or(defaults.env),
+        ..defaults
+    };
+    server_options
+        .select_version(config.server_version.as_deref())
+        .await;
+    let mut razor_unavailable = None;
+    if config.razor.unwrap_or(false) {
+        razor_unavailable = server_options
+            .select_razor_version(config.razor_version.as_deref())
+            .await;
+    }
+    // Validated once Razor is selected, as it reserves arguments of its own
+    server_options
+        .validate()
+        .context("Invalid server options")?;
+
+    if let Some(source) = install_from {
+        let path = install_server_from(&server_options, &source).await?;
         println!("{}", path.to_string_lossy());
-        return;
+        return Ok(0);
     }
 
-    let (mut server_stdin, server_stdout) =
-        start_server(version, args.remove_old_server_versions, directory_path).await;
+    if download {
+        let path = download_server(&server_options).await?;
+        println!("{}", path.to_string_lossy());
+        return Ok(0);
+    }
 
-    let stdin = io::stdin();
+    if let Some(Command::Doctor { json }) = command {
+        let report = diagnose(&server_options, &config, &workspace_dir).await;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print!("{report}");
+        }
+        return Ok(i32::from(report.has_errors()));
+    }
 
-    let stream_to_stdout = async {
-        let mut reader = BufReader::new(server_stdout);
-        let mut stdout = io::stdout();
+    if let Some(Command::Replay { trace_file }) = command {
+        return match replay_file(&trace_file, &server_options).await {
+            Ok(differences) if differences.is_empty() => {
+                println!("The responses match the trace");
+                Ok(0)
+            }
+            Ok(differences) => {
+                print!("{}", report(&differences));
+                println!("{} responses differ from the trace", differences.len());
+                Ok(1)
+            }
+            Err(e) => {
+                error!("{e:#}");
+                eprintln!("{e:?}");
+                Ok(2)
+            }
+        };
+    }
 
-        io::copy(&mut reader, &mut stdout).await
+    let trace = match &trace_file {
+        Some(path) => Trace::create(path)?,
+        None => Trace::default(),
     };
+    let mut proxy = Proxy::new()
+        .trace(trace)
+        .restart_server(
+            config
+                .max_server_restarts
+                .unwrap_or(DEFAULT_MAX_SERVER_RESTARTS),
+        )
+        .with(
+            OpenWorkspace::new(config.solution, config.projects)
+                .selection(config.solution_selection.unwrap_or_default())
+                .preferred_solution(config.preferred_solution),
+        );
+    if server_options.razor_version.is_some() {
+        proxy = proxy.with(Razor::new());
+    }
+    if config.features.restore() {
+        proxy = proxy.with(Restore::new());
+    }
+    if config.features.diagnostic_refresh() {
+        proxy = proxy.with(DiagnosticRefresh::new());
+    }
+    if config.features.push_diagnostics() {
+        proxy = proxy.with(PushDiagnostics::new());
+    }
+    if config.features.source_generated_documents() {
+        proxy = proxy.with(SourceGenerated::new()?);
+    }
+    if config.features.metadata_as_source() {
+        proxy = proxy.with(MetadataAsSource::new()?);
+    }
+    if config.features.workspace_configuration() {
+        proxy = proxy.with(
+            WorkspaceConfiguration::new(config.settings.clone()).watch(workspace_dir.clone()),
+        );
+    }
 
-    let stdin_to_stream = async {
-        let mut stdin = BufReader::new(stdin);
-        loop {
-            let mut buffer = vec![0; 6000];
-            let bytes_read = stdin
-                .read(&mut buffer)
-                .await
-                .expect("Unable to read incoming client notification");
-            if bytes_read == 0 {
-                break; // EOF reached
-            }
-            server_stdin
-                .write_all(&buffer[..bytes_read])
+    let process = ServerProcess::default();
+    let client_reader = client_reader.context("No client to serve")?;
+    let running = proxy.run_with_server(client_reader, io::stdout(), |ctx, initialize| {
+        let server_options = server_options.clone();
+        let process = process.clone();
+        // Shown once, as clients tend to only log warnings
+        if let Some(message) = razor_unavailable.take() {
+            ctx.client
+                .notify("window/showMessage", json!({"type": 2, "message": message}));
+        }
+        async move {
+            let initialize = initialize
                 .await
-                .expect("Unable to forward client notification to server");
+                .context("The client closed before sending initialize")?;
+            let mut server = start_server(&server_options, &ctx.client, &initialize).await?;
+            let server_stdin = server.stdin.take().ok_or(Error::NotPiped)?;
+            let server_stdout = server.stdout.take().ok_or(Error::NotPiped)?;
+            process.replace(server);
+            Ok((BufReader::new(server_stdout), server_stdin))
+        }
+    });
 
-            let notification = String::from_utf8(buffer[..bytes_read].to_vec())
-                .expect("Unable to convert buffer to string");
+    let result = tokio::select! {
+        result = running => result,
+        code = termination_signal() => {
+            info!(code, "Terminated by signal");
+            process.kill().await;
+            return Ok(code);
+        }
+    };
 
-            if notification.contains("initialize") {
-                let open_solution_notification =
-                    create_open_notification(&notification, args.solution_path, args.project_paths);
+    if let Err(e) = &result {
+        error!("{e:#}");
+        eprintln!("{e:?}");
+    }
 
-                server_stdin
-                    .write_all(open_solution_notification.as_bytes())
-                    .await
-                    .expect("Unable to send open solution notification to server");
+    Ok(match process.stop(DEFAULT_EXIT_TIMEOUT).await {
+        Some(status) => status.code().unwrap_or(1),
+        None => i32::from(result.is_err()),
+    })
+}
 
-                break;
-            }
-        }
-        io::copy(&mut stdin, &mut server_stdin).await
-    };
+/// Reads `initialize` ahead of the proxy, so the workspace configuration is looked for in the
+/// first workspace folder of the client rather than the directory the editor started the wrapper
+/// in. Returns the client input with `initialize` put back in front of it.
+async fn read_initialize() -> anyhow::Result<(impl AsyncBufRead + Unpin, Option<PathBuf>)> {
+    let mut reader = MessageReader::new(BufReader::new(io::stdin()));
+    let initialize = reader.read().await?;
 
-    _ = try_join!(stdin_to_stream, stream_to_stdout);
+    let mut framed = vec![];
+    let mut folder = None;
+    if let Some(initialize) = &initialize {
+        MessageWriter::new(&mut framed).write(initialize).await?;
+        folder = workspace_folders(initialize)
+            .ok()
+            .and_then(|folders| folders.into_iter().next());
+    }
+    Ok((
+        BufReader::new(Cursor::new(framed).chain(reader.into_inner())),
+        folder,
+    ))
 }
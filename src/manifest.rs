@@ -0,0 +1,174 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// The files of a server installation, written once the installation is complete.
+///
+/// An installation with files not matching its manifest is damaged, e.g. by an interrupted
+/// download. The manifest only tells whether the files are the ones that were installed, not
+/// whether the package was the one published.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    pub package_id: String,
+    pub version: String,
+    /// SHA-256 of the package the installation was extracted from, for reference
+    pub package_sha256: Option<String>,
+    /// Files relative to the installation directory, separated by `/`
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FileEntry {
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Records every file below `dir`, except in directories with a manifest of their own.
+    pub fn create(
+        dir: &Path,
+        package_id: &str,
+        version: &str,
+        package_sha256: Option<String>,
+    ) -> Result<Manifest> {
+        let mut files = BTreeMap::new();
+        add_files(dir, dir, &mut files)?;
+
+        Ok(Manifest {
+            package_id: package_id.to_string(),
+            version: version.to_string(),
+            package_sha256,
+            files,
+        })
+    }
+
+    /// Whether `dir` has a manifest, i.e. was installed by the wrapper.
+    pub fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST_FILE).exists()
+    }
+
+    pub fn read(dir: &Path) -> Result<Manifest> {
+        let path = dir.join(MANIFEST_FILE);
+        let content =
+            fs::read_to_string(&path).with_context(|| format!("{} is missing", path.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("{} is invalid", path.display()))
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        fs::write(dir.join(MANIFEST_FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Checks that every file exists with its recorded size, and when `thorough` also its hash.
+    pub fn verify(&self, dir: &Path, thorough: bool) -> Result<()> {
+        for (name, entry) in &self.files {
+            let path = dir.join(name);
+            let metadata =
+                fs::metadata(&path).with_context(|| format!("{} is missing", path.display()))?;
+
+            if metadata.len() != entry.size {
+                bail!(
+                    "{} has size {}, expected {}",
+                    path.display(),
+                    metadata.len(),
+                    entry.size
+                );
+            }
+            if thorough && sha256(&path)? != entry.sha256 {
+                bail!("{} does not match its recorded hash", path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn add_files(root: &Path, dir: &Path, files: &mut BTreeMap<String, FileEntry>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if !Manifest::exists(&path) {
+                add_files(root, &path, files)?;
+            }
+            continue;
+        }
+
+        let name = path
+            .strip_prefix(root)?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let entry = FileEntry {
+            size: fs::metadata(&path)?.len(),
+            sha256: sha256(&path)?,
+        };
+        files.insert(name, entry);
+    }
+    Ok(())
+}
+
+pub fn sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn installation() -> (TempDir, Manifest) {
+        let tmp = TempDir::new().unwrap();
+        let server_dir = tmp.path().join("linux-x64");
+        fs::create_dir_all(server_dir.join("cs")).unwrap();
+        fs::write(server_dir.join("Server.dll"), "server").unwrap();
+        fs::write(server_dir.join("cs").join("Resources.dll"), "resources").unwrap();
+
+        let manifest = Manifest::create(tmp.path(), "Server", "1.0.0", None).unwrap();
+        manifest.write(tmp.path()).unwrap();
+        (tmp, manifest)
+    }
+
+    #[test]
+    fn records_files() {
+        let (tmp, manifest) = installation();
+
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            vec!["linux-x64/Server.dll", "linux-x64/cs/Resources.dll"]
+        );
+        assert_eq!(Manifest::read(tmp.path()).unwrap(), manifest);
+        manifest.verify(tmp.path(), true).unwrap();
+    }
+
+    #[test]
+    fn detects_missing_and_truncated_files() {
+        let (tmp, manifest) = installation();
+
+        fs::write(tmp.path().join("linux-x64").join("Server.dll"), "ser").unwrap();
+        assert!(manifest.verify(tmp.path(), false).is_err());
+
+        fs::remove_file(tmp.path().join("linux-x64").join("Server.dll")).unwrap();
+        assert!(manifest.verify(tmp.path(), false).is_err());
+    }
+
+    #[test]
+    fn detects_changed_content_when_thorough() {
+        let (tmp, manifest) = installation();
+
+        fs::write(tmp.path().join("linux-x64").join("Server.dll"), "SERVER").unwrap();
+
+        assert!(manifest.verify(tmp.path(), false).is_ok());
+        assert!(manifest.verify(tmp.path(), true).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+pub mod diagnostic_refresh;
+pub mod metadata_as_source;
+pub mod open_workspace;
+pub mod push_diagnostics;
+pub mod razor;
+pub mod restore;
+mod session_dir;
+pub mod source_generated;
+pub mod workspace_configuration;
+
+use serde_json::Value;
+
+use crate::{proxy::Context, transport::Message};
+
+/// What the proxy should do with a message after a middleware has seen it.
+pub enum Action {
+    /// Pass the (possibly changed) message on to the next middleware and then to its destination.
+    Forward(Message),
+    /// Swallow the message.
+    Drop,
+    /// Swallow the message and send this reply back to where it came from.
+    Reply(Message),
+}
+
+/// A hook into the traffic between the client and `Microsoft.CodeAnalysis.LanguageServer`.
+///
+/// Middlewares run in the order they were added to the proxy, for both directions. New messages
+/// can be injected at any time through the peers on the [`Context`], which may also be cloned into
+/// spawned tasks for work that has to wait on responses.
+pub trait Middleware: Send {
+    fn on_client_message(&mut self, message: Message, _ctx: &Context) -> Action {
+        Action::Forward(message)
+    }
+
+    fn on_server_message(&mut self, message: Message, _ctx: &Context) -> Action {
+        Action::Forward(message)
+    }
+
+    /// Called when the server has stopped and is about to be started again. Anything learned from
+    /// the old server, like the URIs it handed out, no longer applies.
+    fn on_server_restart(&mut self, _ctx: &Context) {}
+}
+
+/// Replaces every string in the value for which `replace` returns a replacement. Object keys are
+/// left alone. Returns whether anything was replaced.
+pub(crate) fn replace_strings(
+    value: &mut Value,
+    replace: &mut impl FnMut(&str) -> Option<String>,
+) -> bool {
+    match value {
+        Value::String(string) => match replace(string) {
+            Some(replacement) => {
+                *string = replacement;
+                true
+            }
+            None => false,
+        },
+        Value::Array(values) => {
+            let mut replaced = false;
+            for value in values {
+                replaced |= replace_strings(value, replace);
+            }
+            replaced
+        }
+        Value::Object(map) => {
+            let mut replaced = false;
+            for value in map.values_mut() {
+                replaced |= replace_strings(value, replace);
+            }
+            replaced
+        }
+        _ => false,
+    }
+}
+
+/// Whether the body of a message contains `needle`, to skip parsing messages that cannot refer to
+/// what a middleware is looking for.
+pub(crate) fn contains(body: &[u8], needle: &[u8]) -> bool {
+    body.windows(needle.len()).any(|window| window == needle)
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use serde_json::{Value, json};
+
+    use crate::proxy::test_support::Endpoint;
+
+    /// Sends a definition request from the client, and answers it from the server with `result`.
+    pub(crate) async fn answer_definition(
+        client: &mut Endpoint,
+        server: &mut Endpoint,
+        id: u64,
+        result: Value,
+    ) {
+        client
+            .send(json!({"jsonrpc": "2.0", "id": id, "method": "textDocument/definition", "params": {}}))
+            .await;
+        assert_eq!(server.receive().await["method"], "textDocument/definition");
+        server
+            .send(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+            .await;
+    }
+
+    /// The URI the server is sent for a hover the client requests on `uri`.
+    pub(crate) async fn hover_uri(
+        client: &mut Endpoint,
+        server: &mut Endpoint,
+        id: u64,
+        uri: &Value,
+    ) -> Value {
+        client
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": "textDocument/hover",
+                "params": {"textDocument": {"uri": uri}}
+            }))
+            .await;
+        server.receive().await["params"]["textDocument"]["uri"].clone()
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use serde_json::{Value, json};
+
+use crate::{
+    middleware::{Action, Middleware},
+    proxy::{Context, Peer},
+    transport::Message,
+};
+
+const PROJECT_INITIALIZATION_COMPLETE: &str = "workspace/projectInitializationComplete";
+
+/// Refreshes diagnostics once the server has loaded the projects.
+///
+/// Diagnostics pulled before that only contain errors that do not need a loaded project. Clients
+/// supporting `workspace/diagnostic/refresh` are asked to pull again, for others the diagnostics of
+/// the open documents are pulled by the proxy and published.
+#[derive(Default)]
+pub struct DiagnosticRefresh {
+    refresh_support: bool,
+    open_documents: HashSet<String>,
+}
+
+impl DiagnosticRefresh {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Middleware for DiagnosticRefresh {
+    fn on_client_message(&mut self, message: Message, _ctx: &Context) -> Action {
+        match message.method() {
+            Some("initialize") => {
+                self.refresh_support = message
+                    .params()
+                    .and_then(|p| p.pointer("/capabilities/workspace/diagnostic/refreshSupport"))
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
+            }
+            Some("textDocument/didOpen") => {
+                if let Some(uri) = document_uri(&message) {
+                    self.open_documents.insert(uri.to_string());
+                }
+            }
+            Some("textDocument/didClose") => {
+                if let Some(uri) = document_uri(&message) {
+                    self.open_documents.remove(uri);
+                }
+            }
+            _ => {}
+        }
+        Action::Forward(message)
+    }
+
+    fn on_server_message(&mut self, message: Message, ctx: &Context) -> Action {
+        if message.method() != Some(PROJECT_INITIALIZATION_COMPLETE) {
+            return Action::Forward(message);
+        }
+
+        if self.refresh_support {
+            let client = ctx.client.clone();
+            tokio::spawn(async move {
+                _ = client
+                    .request("workspace/diagnostic/refresh", Value::Null)
+                    .await;
+            });
+        } else {
+            for uri in &self.open_documents {
+                let ctx = ctx.clone();
+                let uri = uri.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = publish_pulled_diagnostics(&ctx, &uri).await {
+                        ctx.client.notify(
+                            "window/logMessage",
+                            json!({"type": 2, "message": format!("{e:#}")}),
+                        );
+                    }
+                });
+            }
+        }
+
+        Action::Forward(message)
+    }
+}
+
+pub(crate) fn document_uri(message: &Message) -> Option<&str> {
+    message.params()?.pointer("/textDocument/uri")?.as_str()
+}
+
+/// Pulls the diagnostics of a document from the server and publishes them to the client, unless
+/// they are unchanged, so the client keeps the ones published last.
+pub(crate) async fn publish_pulled_diagnostics(ctx: &Context, uri: &str) -> Result<()> {
+    let Some(diagnostics) = pull_diagnostics(&ctx.server, uri).await? else {
+        return Ok(());
+    };
+    ctx.client.notify(
+        "textDocument/publishDiagnostics",
+        json!({"uri": uri, "diagnostics": diagnostics}),
+    );
+    Ok(())
+}
+
+/// The items of a full report, or `None` for an unchanged report.
+async fn pull_diagnostics(server: &Peer, uri: &str) -> Result<Option<Value>> {
+    let report = server
+        .request(
+            "textDocument/diagnostic",
+            json!({"textDocument": {"uri": uri}}),
+        )
+        .await?;
+
+    if report.get("kind").and_then(Value::as_str) == Some("unchanged") {
+        return Ok(None);
+    }
+    Ok(Some(
+        report.get("items").cloned().unwrap_or_else(|| json!([])),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::{Proxy, test_support::start};
+
+    fn initialize(refresh_support: bool) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": {"capabilities": {"workspace": {"diagnostic": {"refreshSupport": refresh_support}}}}
+        })
+    }
+
+    fn did_open(uri: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": uri, "languageId": "csharp", "version": 0, "text": ""}}
+        })
+    }
+
+    fn initialization_complete() -> Value {
+        json!({"jsonrpc": "2.0", "method": PROJECT_INITIALIZATION_COMPLETE, "params": {}})
+    }
+
+    #[tokio::test]
+    async fn asks_client_to_refresh() {
+        let (mut client, mut server, _) = start(Proxy::new().with(DiagnosticRefresh::new()));
+
+        client.send(initialize(true)).await;
+        assert_eq!(server.receive().await["method"], "initialize");
+
+        server.send(initialization_complete()).await;
+
+        assert_eq!(
+            client.receive().await["method"],
+            PROJECT_INITIALIZATION_COMPLETE
+        );
+        assert_eq!(
+            client.receive().await["method"],
+            "workspace/diagnostic/refresh"
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_diagnostics_without_refresh_support() {
+        let (mut client, mut server, _) = start(Proxy::new().with(DiagnosticRefresh::new()));
+
+        client.send(initialize(false)).await;
+        client.send(did_open("file:///Program.cs")).await;
+        assert_eq!(server.receive().await["method"], "initialize");
+        assert_eq!(server.receive().await["method"], "textDocument/didOpen");
+
+        server.send(initialization_complete()).await;
+        assert_eq!(
+            client.receive().await["method"],
+            PROJECT_INITIALIZATION_COMPLETE
+        );
+
+        let pull = server.receive().await;
+        assert_eq!(pull["method"], "textDocument/diagnostic");
+        assert_eq!(pull["params"]["textDocument"]["uri"], "file:///Program.cs");
+        server
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": pull["id"],
+                "result": {"kind": "full", "items": [{"message": "; expected"}]}
+            }))
+            .await;
+
+        let published = client.receive().await;
+        assert_eq!(published["method"], "textDocument/publishDiagnostics");
+        assert_eq!(published["params"]["uri"], "file:///Program.cs");
+        assert_eq!(
+            published["params"]["diagnostics"][0]["message"],
+            "; expected"
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{Context as _, Result};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    middleware::{
+        Action, Middleware, contains, replace_strings,
+        session_dir::{SessionDir, set_readonly, short_hash},
+    },
+    path::{file_path, file_uri},
+    proxy::Context,
+    transport::Message,
+};
+
+/// The directory below the temporary directory the server decompiles metadata into.
+const METADATA_DIR: &str = "MetadataAsSource";
+
+/// Gives the client its own read-only copies of the documents the server decompiles from
+/// metadata.
+///
+/// Navigating into framework or package types leads to documents the server keeps in the
+/// `MetadataAsSource` directory in the temporary directory, with paths editors do not treat as
+/// part of the workspace and that the server may reuse. Server messages referring to them get the
+/// URI of a read-only copy in a directory of the session instead, and client messages referring
+/// to a copy are sent to the server with the URI of its document. Documents are copied again when
+/// they change, and copied outside of the dispatch, so responses wait for their copies while
+/// notifications and requests of the server keep their order with the copies made so far.
+pub struct MetadataAsSource {
+    dir: SessionDir,
+    /// The directory the server decompiles into
+    metadata_dir: PathBuf,
+    copies: Arc<Mutex<Copies>>,
+}
+
+#[derive(Default)]
+struct Copies {
+    /// Copies by the URI of the server
+    copies: HashMap<String, Copy>,
+    /// URIs of the server by the URI of the copy
+    documents: HashMap<String, String>,
+}
+
+struct Copy {
+    uri: String,
+    /// Size and modification time of the document when it was copied
+    copied: (u64, Option<SystemTime>),
+}
+
+impl MetadataAsSource {
+    /// Writes the copies to a new private directory in the temporary directory.
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            dir: SessionDir::new("metadata")?,
+            metadata_dir: env::temp_dir().join(METADATA_DIR),
+            copies: Arc::default(),
+        })
+    }
+
+    /// Writes the copies below `dir`, which must not exist yet and is removed with the middleware.
+    pub fn at(dir: PathBuf) -> Result<Self> {
+        Ok(Self {
+            dir: SessionDir::at(dir)?,
+            metadata_dir: env::temp_dir().join(METADATA_DIR),
+            copies: Arc::default(),
+        })
+    }
+
+    /// Copies the documents the server decompiles into `dir` instead of `MetadataAsSource` in the
+    /// temporary directory.
+    pub fn metadata_dir(mut self, dir: PathBuf) -> Self {
+        self.metadata_dir = dir;
+        self
+    }
+}
+
+/// The URI of the copy of a metadata document, copying it when it was not copied yet or has
+/// changed since.
+fn copy(dir: &Path, copies: &Mutex<Copies>, uri: &str) -> Result<String> {
+    let source = file_path(uri)?;
+    let metadata =
+        fs::metadata(&source).with_context(|| format!("Unable to read {}", source.display()))?;
+    let copied = (metadata.len(), metadata.modified().ok());
+    if let Some(copy) = copies.lock().unwrap().copies.get(uri)
+        && copy.copied == copied
+    {
+        return Ok(copy.uri.clone());
+    }
+
+    let name = source
+        .file_name()
+        .with_context(|| format!("{} has no file name", source.display()))?;
+    let target = dir.join(short_hash(uri)).join(name);
+    fs::create_dir_all(dir.join(short_hash(uri)))?;
+    if target.exists() {
+        set_readonly(&target, false)?;
+    }
+    fs::copy(&source, &target).with_context(|| format!("Unable to copy {}", source.display()))?;
+    set_readonly(&target, true)?;
+
+    let copy = Copy {
+        uri: file_uri(&target)?,
+        copied,
+    };
+    let copy_uri = copy.uri.clone();
+    let mut copies = copies.lock().unwrap();
+    copies.documents.insert(copy_uri.clone(), uri.to_string());
+    copies.copies.insert(uri.to_string(), copy);
+    Ok(copy_uri)
+}
+
+/// Copies the metadata documents in `json` and replaces their URIs with the URIs of the copies.
+fn replace_with_copies(dir: &Path, metadata_dir: &Path, copies: &Mutex<Copies>, json: &mut Value) {
+    replace_strings(json, &mut |uri| {
+        if !is_metadata_document(metadata_dir, uri) {
+            return None;
+        }
+        copy(dir, copies, uri)
+            .inspect_err(|e| warn!(uri, "Unable to copy metadata document: {e:#}"))
+            .ok()
+    });
+}
+
+impl Middleware for MetadataAsSource {
+    fn on_client_message(&mut self, message: Message, _ctx: &Context) -> Action {
+        if !contains(message.body(), self.dir.uri_name()) {
+            return Action::Forward(message);
+        }
+        let Some(mut json) = message.json().cloned() else {
+            return Action::Forward(message);
+        };
+
+        // Locked for each lookup, so copies made meanwhile do not wait for the whole message
+        let replaced = replace_strings(&mut json, &mut |uri| {
+            self.copies.lock().unwrap().documents.get(uri).cloned()
+        });
+        if replaced {
+            Action::Forward(Message::from_value(json))
+        } else {
+            Action::Forward(message)
+        }
+    }
+
+    fn on_server_message(&mut self, message: Message, ctx: &Context) -> Action {
+        if !contains(message.body(), METADATA_DIR.as_bytes()) {
+            return Action::Forward(message);
+        }
+        let Some(mut json) = message.json().cloned() else {
+            return Action::Forward(message);
+        };
+
+        if !message.is_response() {
+            let mut documents = HashSet::new();
+            let replaced = {
+                let copies = self.copies.lock().unwrap();
+                replace_strings(&mut json, &mut |uri| {
+                    if is_metadata_document(&self.metadata_dir, uri) {
+                        documents.insert(uri.to_string());
+                    }
+                    copies.copies.get(uri).map(|copy| copy.uri.clone())
+                })
+            };
+            // Copied for the messages to come
+            if !documents.is_empty() {
+                let dir = self.dir.path().to_path_buf();
+                let copies = self.copies.clone();
+                tokio::task::spawn_blocking(move || {
+                    for uri in documents {
+                        if let Err(e) = copy(&dir, &copies, &uri) {
+                            warn!(uri, "Unable to copy metadata document: {e:#}");
+                        }
+                    }
+                });
+            }
+            return if replaced {
+                Action::Forward(Message::from_value(json))
+            } else {
+                Action::Forward(message)
+            };
+        }
+
+        let ctx = ctx.clone();
+        let dir = self.dir.path().to_path_buf();
+        let metadata_dir = self.metadata_dir.clone();
+        let copies = self.copies.clone();
+        tokio::spawn(async move {
+            let json = tokio::task::spawn_blocking(move || {
+                replace_with_copies(&dir, &metadata_dir, &copies, &mut json);
+                json
+            })
+            .await;
+            match json {
+                Ok(json) => ctx.client.send(Message::from_value(json)),
+                Err(e) => warn!("Unable to copy metadata documents: {e}"),
+            }
+        });
+        Action::Drop
+    }
+
+    fn on_server_restart(&mut self, _ctx: &Context) {
+        let mut copies = self.copies.lock().unwrap();
+        copies.copies.clear();
+        copies.documents.clear();
+    }
+}
+
+/// Whether the URI is a file in the directory the server decompiles into.
+fn is_metadata_document(metadata_dir: &Path, uri: &str) -> bool {
+    uri.starts_with("file:") && file_path(uri).is_ok_and(|path| path.starts_with(metadata_dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        middleware::test_support::{answer_definition, hover_uri},
+        proxy::{Proxy, test_support::start},
+    };
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn copies_metadata_documents() {
+        let tmp = TempDir::new().unwrap();
+        let decompiled = tmp
+            .path()
+            .join(METADATA_DIR)
+            .join("4a1b")
+            .join("Console.cs");
+        fs::create_dir_all(decompiled.parent().unwrap()).unwrap();
+        fs::write(&decompiled, "public static class Console {}").unwrap();
+        let decompiled_uri = file_uri(&decompiled).unwrap();
+        let dir = tmp.path().join("metadata");
+        let (mut client, mut server, _) = start(
+            Proxy::new().with(
+                MetadataAsSource::at(dir.clone())
+                    .unwrap()
+                    .metadata_dir(tmp.path().join(METADATA_DIR)),
+            ),
+        );
+
+        answer_definition(
+            &mut client,
+            &mut server,
+            1,
+            json!([{"uri": decompiled_uri, "range": {}}]),
+        )
+        .await;
+
+        let definition = client.receive().await;
+        let copy_uri = definition["result"][0]["uri"].as_str().unwrap().to_string();
+        let copy = file_path(&copy_uri).unwrap();
+        assert!(copy.starts_with(&dir));
+        assert_eq!(
+            fs::read_to_string(&copy).unwrap(),
+            "public static class Console {}"
+        );
+        assert!(fs::metadata(&copy).unwrap().permissions().readonly());
+
+        assert_eq!(
+            hover_uri(&mut client, &mut server, 2, &json!(copy_uri)).await,
+            decompiled_uri
+        );
+
+        fs::write(
+            &decompiled,
+            "public static class Console { void WriteLine() {} }",
+        )
+        .unwrap();
+        answer_definition(&mut client, &mut server, 3, json!({"uri": decompiled_uri})).await;
+        assert_eq!(client.receive().await["result"]["uri"], copy_uri);
+        assert_eq!(
+            fs::read_to_string(&copy).unwrap(),
+            "public static class Console { void WriteLine() {} }"
+        );
+    }
+
+    #[test]
+    fn recognizes_metadata_documents() {
+        let tmp = TempDir::new().unwrap();
+        let metadata_dir = tmp.path().join(METADATA_DIR);
+        let decompiled = metadata_dir.join("4a1b").join("Console.cs");
+        let other = tmp.path().join("src").join("Program.cs");
+        let in_workspace = tmp.path().join("src").join(METADATA_DIR).join("A.cs");
+
+        assert!(is_metadata_document(
+            &metadata_dir,
+            &file_uri(&decompiled).unwrap()
+        ));
+        assert!(!is_metadata_document(
+            &metadata_dir,
+            &file_uri(&other).unwrap()
+        ));
+        assert!(!is_metadata_document(
+            &metadata_dir,
+            &file_uri(&in_workspace).unwrap()
+        ));
+        assert!(!is_metadata_document(
+            &metadata_dir,
+            "roslyn-source-generated://MetadataAsSource/A.cs"
+        ));
+    }
+}
@@ -0,0 +1,456 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::{
+    middleware::{Action, Middleware, diagnostic_refresh::document_uri},
+    path::{
+        file_path, find_solutions, folder_paths, open_projects_notification,
+        open_solution_notification, workspace_folders,
+    },
+    proxy::Context,
+    solution::{SolutionMemory, SolutionSelection, find_containing, preselect},
+    transport::Message,
+};
+
+/// Sends `solution/open` or `project/open` for every workspace folder once the server has answered
+/// `initialize`, and for every folder added later on.
+///
+/// The solution and project overrides only apply to the first workspace folder.
+pub struct OpenWorkspace {
+    solution_override: Option<String>,
+    projects_override: Option<Vec<String>>,
+    selection: SolutionSelection,
+    preferred_solution: Option<String>,
+    memory: SolutionMemory,
+    state: State,
+    /// Folders waiting for a document to be opened before picking their solution
+    waiting_for_document: Vec<(PathBuf, Vec<PathBuf>)>,
+}
+
+enum State {
+    WaitingForInitialize,
+    Initializing { id: Value, folders: Vec<PathBuf> },
+    Initialized,
+}
+
+impl OpenWorkspace {
+    pub fn new(solution_override: Option<String>, projects_override: Option<Vec<String>>) -> Self {
+        Self {
+            solution_override,
+            projects_override,
+            selection: SolutionSelection::default(),
+            preferred_solution: None,
+            memory: SolutionMemory::default(),
+            state: State::WaitingForInitialize,
+            waiting_for_document: vec![],
+        }
+    }
+
+    /// How to pick between several solutions in the workspace.
+    pub fn selection(mut self, selection: SolutionSelection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Open the solution with this name when the workspace contains it.
+    pub fn preferred_solution(mut self, name: Option<String>) -> Self {
+        self.preferred_solution = name;
+        self
+    }
+
+    pub fn memory(mut self, memory: SolutionMemory) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    fn open(&mut self, root_path: PathBuf, ctx: &Context) {
+        if let Some(solution) = self.solution_override.take() {
+            open_solution(ctx, &root_path.join(solution.trim()), "configured");
+            return;
+        }
+
+        let candidates = find_solutions(&root_path);
+        if candidates.is_empty() {
+            info!(folder = %root_path.display(), "No solution found, opening projects");
+            match open_projects_notification(&root_path, self.projects_override.take()) {
+                Ok(notification) => ctx.server.send(notification),
+                Err(e) => warn!("Unable to open projects: {e}"),
+            }
+            return;
+        }
+        self.projects_override = None;
+
+        let preselected = preselect(
+            &candidates,
+            self.preferred_solution.as_deref(),
+            &self.memory,
+            &root_path,
+        );
+        if let Some(solution) = preselected {
+            open_solution(ctx, &solution, "preselected");
+            return;
+        }
+
+        match self.selection {
+            SolutionSelection::First => {
+                open_solution(ctx, &candidates[0], "first");
+            }
+            SolutionSelection::Document => {
+                self.waiting_for_document.push((root_path, candidates));
+            }
+            SolutionSelection::Ask => {
+                let ctx = ctx.clone();
+                let memory = self.memory.clone();
+                tokio::spawn(async move {
+                    match ask_for_solution(&ctx, &root_path, &candidates).await {
+                        Some(solution) => {
+                            remember(&memory, &root_path, &solution, &ctx);
+                            open_solution(&ctx, &solution, "asked");
+                        }
+                        // Not remembered, so the user is asked again next time
+                        None => open_solution(&ctx, &candidates[0], "first"),
+                    }
+                });
+            }
+        }
+    }
+
+    fn open_for_document(&mut self, document: &Path, ctx: &Context) {
+        let Some(index) = self
+            .waiting_for_document
+            .iter()
+            .position(|(root_path, _)| document.starts_with(root_path))
+        else {
+            return;
+        };
+        let (root_path, candidates) = self.waiting_for_document.remove(index);
+
+        match find_containing(&candidates, document) {
+            Some(solution) => {
+                remember(&self.memory, &root_path, solution, ctx);
+                open_solution(ctx, solution, "document");
+            }
+            None => open_solution(ctx, &candidates[0], "first"),
+        }
+    }
+
+    fn remove(&mut self, root_path: &Path, ctx: &Context) {
+        self.waiting_for_document
+            .retain(|(waiting, _)| waiting != root_path);
+
+        // The server has no way of unloading projects
+        ctx.client.notify(
+            "window/logMessage",
+            json!({
+                "type": 3,
+                "message": format!(
+                    "Projects in {} stay loaded until the language server is restarted",
+                    root_path.display()
+                ),
+            }),
+        );
+    }
+}
+
+impl Middleware for OpenWorkspace {
+    fn on_client_message(&mut self, message: Message, ctx: &Context) -> Action {
+        match message.method() {
+            Some("initialize") => {
+                if let (State::WaitingForInitialize, Some(id)) = (&self.state, message.id()) {
+                    self.state = State::Initializing {
+                        id: id.clone(),
+                        folders: workspace_folders(&message).unwrap_or_else(|e| {
+                            warn!("No workspace is opened: {e}");
+                            vec![]
+                        }),
+                    };
+                }
+            }
+            Some("textDocument/didOpen") => {
+                if let Some(document) = document_uri(&message).and_then(|uri| file_path(uri).ok()) {
+                    self.open_for_document(&document, ctx);
+                }
+            }
+            Some("workspace/didChangeWorkspaceFolders") => {
+                let event = message
+                    .params()
+                    .map(|params| params["event"].clone())
+                    .unwrap_or_default();
+                if let State::Initializing { folders, .. } = &mut self.state {
+                    let removed = folder_paths(&event["removed"]);
+                    folders.retain(|folder| !removed.contains(folder));
+                    folders.extend(folder_paths(&event["added"]));
+                } else {
+                    for folder in folder_paths(&event["removed"]) {
+                        self.remove(&folder, ctx);
+                    }
+                    for folder in folder_paths(&event["added"]) {
+                        self.open(folder, ctx);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Action::Forward(message)
+    }
+
+    fn on_server_message(&mut self, message: Message, ctx: &Context) -> Action {
+        if let State::Initializing { id, .. } = &self.state
+            && message.is_response()
+            && message.id() == Some(id)
+            && let State::Initializing { folders, .. } =
+                std::mem::replace(&mut self.state, State::Initialized)
+        {
+            for folder in folders {
+                self.open(folder, ctx);
+            }
+        }
+        Action::Forward(message)
+    }
+}
+
+/// Opens the solution picked for a workspace folder, with how it was picked.
+fn open_solution(ctx: &Context, solution: &Path, selection: &str) {
+    info!(solution = %solution.display(), selection, "Opening solution");
+    match open_solution_notification(solution) {
+        Ok(notification) => ctx.server.send(notification),
+        Err(e) => warn!("Unable to open solution: {e}"),
+    }
+}
+
+/// Asks the user which solution to open. Returns `None` when the question is dismissed or the
+/// client cannot ask.
+async fn ask_for_solution(
+    ctx: &Context,
+    root_path: &Path,
+    candidates: &[PathBuf],
+) -> Option<PathBuf> {
+    let titles: Vec<String> = candidates
+        .iter()
+        .map(|candidate| {
+            candidate
+                .strip_prefix(root_path)
+                .unwrap_or(candidate)
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+    let actions: Vec<Value> = titles.iter().map(|title| json!({"title": title})).collect();
+
+    let answer = ctx
+        .client
+        .request(
+            "window/showMessageRequest",
+            json!({
+                "type": 3,
+                "message": "Several solutions were found. Which one should be opened?",
+                "actions": actions,
+            }),
+        )
+        .await;
+
+    let title = answer.ok()?.get("title")?.as_str()?.to_string();
+    let chosen = titles.iter().position(|t| *t == title)?;
+    Some(candidates[chosen].clone())
+}
+
+fn remember(memory: &SolutionMemory, root_path: &Path, solution: &Path, ctx: &Context) {
+    if let Err(e) = memory.remember(root_path, solution) {
+        ctx.client.notify(
+            "window/logMessage",
+            json!({"type": 2, "message": format!("Unable to remember solution: {e:#}")}),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::{Proxy, test_support::start};
+    use std::fs;
+    use tempfile::TempDir;
+    use url::Url;
+
+    struct Workspace {
+        dir: TempDir,
+        api: PathBuf,
+        worker: PathBuf,
+    }
+
+    fn workspace() -> Workspace {
+        let dir = TempDir::new().unwrap();
+        let api = dir.path().join("Api").join("Api.sln");
+        let worker = dir.path().join("Worker").join("Worker.sln");
+        for solution in [&api, &worker] {
+            fs::create_dir_all(solution.parent().unwrap()).unwrap();
+            fs::write(solution, "").unwrap();
+        }
+        Workspace { dir, api, worker }
+    }
+
+    fn uri(path: &Path) -> String {
+        Url::from_file_path(path).unwrap().to_string()
+    }
+
+    fn proxy(workspace: &Workspace, selection: SolutionSelection) -> Proxy {
+        Proxy::new().with(
+            OpenWorkspace::new(None, None)
+                .selection(selection)
+                .memory(SolutionMemory::at(workspace.dir.path().join("memory.json"))),
+        )
+    }
+
+    async fn initialize(
+        workspace: &Workspace,
+        client: &mut crate::proxy::test_support::Endpoint,
+        server: &mut crate::proxy::test_support::Endpoint,
+    ) {
+        client
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "method": "initialize",
+                "params": {"rootUri": uri(workspace.dir.path()), "capabilities": {}}
+            }))
+            .await;
+        assert_eq!(server.receive().await["method"], "initialize");
+        server
+            .send(json!({"jsonrpc": "2.0", "id": 0, "result": {"capabilities": {}}}))
+            .await;
+        assert_eq!(client.receive().await["id"], 0);
+    }
+
+    #[tokio::test]
+    async fn opens_first_solution() {
+        let workspace = workspace();
+        let (mut client, mut server, _) = start(proxy(&workspace, SolutionSelection::First));
+
+        initialize(&workspace, &mut client, &mut server).await;
+
+        let open = server.receive().await;
+        assert_eq!(open["method"], "solution/open");
+        assert_eq!(open["params"]["solution"], uri(&workspace.api));
+    }
+
+    #[tokio::test]
+    async fn opens_solution_of_first_document() {
+        let workspace = workspace();
+        let (mut client, mut server, _) = start(proxy(&workspace, SolutionSelection::Document));
+
+        initialize(&workspace, &mut client, &mut server).await;
+
+        let document = workspace.dir.path().join("Worker").join("Program.cs");
+        client
+            .send(json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {"textDocument": {"uri": uri(&document), "languageId": "csharp", "version": 0, "text": ""}}
+            }))
+            .await;
+
+        let open = server.receive().await;
+        assert_eq!(open["method"], "solution/open");
+        assert_eq!(open["params"]["solution"], uri(&workspace.worker));
+        assert_eq!(server.receive().await["method"], "textDocument/didOpen");
+
+        let memory = SolutionMemory::at(workspace.dir.path().join("memory.json"));
+        assert_eq!(memory.get(workspace.dir.path()), Some(workspace.worker));
+    }
+
+    #[tokio::test]
+    async fn opens_every_workspace_folder() {
+        let workspace = workspace();
+        let (mut client, mut server, _) = start(proxy(&workspace, SolutionSelection::First));
+        let api_folder = workspace.api.parent().unwrap();
+        let worker_folder = workspace.worker.parent().unwrap();
+
+        client
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "method": "initialize",
+                "params": {
+                    "rootUri": uri(workspace.dir.path()),
+                    "workspaceFolders": [{"name": "Api", "uri": uri(api_folder)}],
+                    "capabilities": {}
+                }
+            }))
+            .await;
+        assert_eq!(server.receive().await["method"], "initialize");
+        server
+            .send(json!({"jsonrpc": "2.0", "id": 0, "result": {"capabilities": {}}}))
+            .await;
+
+        let open = server.receive().await;
+        assert_eq!(open["params"]["solution"], uri(&workspace.api));
+
+        client
+            .send(json!({
+                "jsonrpc": "2.0",
+                "method": "workspace/didChangeWorkspaceFolders",
+                "params": {"event": {"added": [{"name": "Worker", "uri": uri(worker_folder)}], "removed": []}}
+            }))
+            .await;
+
+        let open = server.receive().await;
+        assert_eq!(open["params"]["solution"], uri(&workspace.worker));
+        assert_eq!(
+            server.receive().await["method"],
+            "workspace/didChangeWorkspaceFolders"
+        );
+    }
+
+    #[tokio::test]
+    async fn asks_user_for_solution() {
+        let workspace = workspace();
+        let (mut client, mut server, _) = start(proxy(&workspace, SolutionSelection::Ask));
+
+        initialize(&workspace, &mut client, &mut server).await;
+
+        let question = client.receive().await;
+        assert_eq!(question["method"], "window/showMessageRequest");
+        let answer = &question["params"]["actions"][1];
+        client
+            .send(json!({"jsonrpc": "2.0", "id": question["id"], "result": answer}))
+            .await;
+
+        let open = server.receive().await;
+        assert_eq!(open["params"]["solution"], uri(&workspace.worker));
+        let memory = SolutionMemory::at(workspace.dir.path().join("memory.json"));
+        assert_eq!(memory.get(workspace.dir.path()), Some(workspace.worker));
+    }
+
+    #[tokio::test]
+    async fn does_not_remember_dismissed_question() {
+        let workspace = workspace();
+        let (mut client, mut server, _) = start(proxy(&workspace, SolutionSelection::Ask));
+
+        initialize(&workspace, &mut client, &mut server).await;
+
+        let question = client.receive().await;
+        client
+            .send(json!({"jsonrpc": "2.0", "id": question["id"], "result": null}))
+            .await;
+
+        let open = server.receive().await;
+        assert_eq!(open["params"]["solution"], uri(&workspace.api));
+        let memory = SolutionMemory::at(workspace.dir.path().join("memory.json"));
+        assert_eq!(memory.get(workspace.dir.path()), None);
+    }
+
+    #[tokio::test]
+    async fn reuses_remembered_solution() {
+        let workspace = workspace();
+        SolutionMemory::at(workspace.dir.path().join("memory.json"))
+            .remember(workspace.dir.path(), &workspace.worker)
+            .unwrap();
+        let (mut client, mut server, _) = start(proxy(&workspace, SolutionSelection::Ask));
+
+        initialize(&workspace, &mut client, &mut server).await;
+
+        let open = server.receive().await;
+        assert_eq!(open["params"]["solution"], uri(&workspace.worker));
+    }
+}
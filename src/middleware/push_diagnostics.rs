@@ -0,0 +1,302 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde_json::{Value, json};
+use tokio::task::JoinHandle;
+
+use crate::{
+    middleware::{
+        Action, Middleware,
+        diagnostic_refresh::{document_uri, publish_pulled_diagnostics},
+    },
+    proxy::Context,
+    transport::Message,
+};
+
+/// How long a document has to be left alone before its diagnostics are pulled.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Publishes diagnostics to clients that do not support pulling them.
+///
+/// The server only offers `textDocument/diagnostic`, so for clients without the pull capability
+/// the proxy pulls diagnostics itself whenever a document changes and sends them as
+/// `textDocument/publishDiagnostics`.
+#[derive(Default)]
+pub struct PushDiagnostics {
+    enabled: bool,
+    /// The latest diagnostic pull of every open document
+    pulls: HashMap<String, JoinHandle<()>>,
+}
+
+impl PushDiagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pulls diagnostics for a document after the debounce delay, cancelling any older pull.
+    fn schedule(&mut self, uri: &str, ctx: &Context) {
+        self.cancel(uri);
+
+        let ctx = ctx.clone();
+        let task_uri = uri.to_string();
+        let pull = tokio::spawn(async move {
+            tokio::time::sleep(DEBOUNCE).await;
+            if let Err(e) = publish_pulled_diagnostics(&ctx, &task_uri).await {
+                ctx.client.notify(
+                    "window/logMessage",
+                    json!({"type": 2, "message": format!("{e:#}")}),
+                );
+            }
+        });
+        self.pulls.insert(uri.to_string(), pull);
+    }
+
+    fn cancel(&mut self, uri: &str) {
+        if let Some(pull) = self.pulls.remove(uri) {
+            pull.abort();
+        }
+    }
+}
+
+impl Middleware for PushDiagnostics {
+    fn on_client_message(&mut self, mut message: Message, ctx: &Context) -> Action {
+        match message.method() {
+            Some("initialize") => {
+                let pull_support = message
+                    .params()
+                    .and_then(|p| p.pointer("/capabilities/textDocument/diagnostic"))
+                    .is_some_and(|d| !d.is_null());
+                self.enabled = !pull_support;
+
+                if self.enabled {
+                    message = with_pull_capability(message);
+                }
+            }
+            Some("textDocument/didOpen" | "textDocument/didChange" | "textDocument/didSave")
+                if self.enabled =>
+            {
+                if let Some(uri) = document_uri(&message).map(str::to_string) {
+                    self.schedule(&uri, ctx);
+                }
+            }
+            Some("textDocument/didClose") if self.enabled => {
+                if let Some(uri) = document_uri(&message).map(str::to_string) {
+                    self.cancel(&uri);
+                    ctx.client.notify(
+                        "textDocument/publishDiagnostics",
+                        json!({"uri": uri, "diagnostics": []}),
+                    );
+                }
+            }
+            _ => {}
+        }
+        Action::Forward(message)
+    }
+
+    fn on_server_message(&mut self, message: Message, ctx: &Context) -> Action {
+        if !self.enabled || message.method() != Some("workspace/diagnostic/refresh") {
+            return Action::Forward(message);
+        }
+        let Some(id) = message.id().cloned() else {
+            return Action::Forward(message);
+        };
+
+        let uris: Vec<String> = self.pulls.keys().cloned().collect();
+        for uri in uris {
+            self.schedule(&uri, ctx);
+        }
+        ctx.server.send(Message::response(id, Value::Null));
+
+        Action::Drop
+    }
+}
+
+/// Tells the server that the client pulls diagnostics and supports being asked to pull them
+/// again, as the proxy does both on its behalf.
+fn with_pull_capability(message: Message) -> Message {
+    let Some(mut json) = message.json().cloned() else {
+        return message;
+    };
+    let Some(capabilities) = json
+        .pointer_mut("/params/capabilities")
+        .and_then(Value::as_object_mut)
+    else {
+        return message;
+    };
+
+    let text_document = capabilities
+        .entry("textDocument")
+        .or_insert_with(|| json!({}));
+    if let Some(text_document) = text_document.as_object_mut() {
+        text_document.insert(
+            "diagnostic".to_string(),
+            json!({"dynamicRegistration": false, "relatedDocumentSupport": false}),
+        );
+    }
+    let workspace = capabilities.entry("workspace").or_insert_with(|| json!({}));
+    if let Some(workspace) = workspace.as_object_mut() {
+        workspace.insert("diagnostic".to_string(), json!({"refreshSupport": true}));
+    }
+
+    Message::from_value(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::{Proxy, test_support::start};
+
+    fn initialize() -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": {"capabilities": {"textDocument": {"publishDiagnostics": {}}}}
+        })
+    }
+
+    fn did_open(uri: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": uri, "languageId": "csharp", "version": 0, "text": ""}}
+        })
+    }
+
+    fn did_change(uri: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {"textDocument": {"uri": uri, "version": 1}, "contentChanges": []}
+        })
+    }
+
+    #[tokio::test]
+    async fn publishes_pulled_diagnostics() {
+        let (mut client, mut server, _) = start(Proxy::new().with(PushDiagnostics::new()));
+
+        client.send(initialize()).await;
+        let initialize = server.receive().await;
+        assert!(
+            initialize
+                .pointer("/params/capabilities/textDocument/diagnostic")
+                .is_some()
+        );
+        assert_eq!(
+            initialize.pointer("/params/capabilities/workspace/diagnostic/refreshSupport"),
+            Some(&json!(true))
+        );
+
+        client.send(did_open("file:///Program.cs")).await;
+        assert_eq!(server.receive().await["method"], "textDocument/didOpen");
+
+        let pull = server.receive().await;
+        assert_eq!(pull["method"], "textDocument/diagnostic");
+        server
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": pull["id"],
+                "result": {"kind": "full", "items": [{"message": "; expected"}]}
+            }))
+            .await;
+
+        let published = client.receive().await;
+        assert_eq!(published["method"], "textDocument/publishDiagnostics");
+        assert_eq!(
+            published["params"]["diagnostics"][0]["message"],
+            "; expected"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_diagnostics_when_unchanged() {
+        let (mut client, mut server, _) = start(Proxy::new().with(PushDiagnostics::new()));
+
+        client.send(initialize()).await;
+        client.send(did_open("file:///Program.cs")).await;
+        assert_eq!(server.receive().await["method"], "initialize");
+        assert_eq!(server.receive().await["method"], "textDocument/didOpen");
+
+        let pull = server.receive().await;
+        server
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": pull["id"],
+                "result": {"kind": "full", "resultId": "1", "items": [{"message": "; expected"}]}
+            }))
+            .await;
+        assert_eq!(
+            client.receive().await["params"]["diagnostics"][0]["message"],
+            "; expected"
+        );
+
+        client.send(did_change("file:///Program.cs")).await;
+        assert_eq!(server.receive().await["method"], "textDocument/didChange");
+        let pull = server.receive().await;
+        server
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": pull["id"],
+                "result": {"kind": "unchanged", "resultId": "1"}
+            }))
+            .await;
+
+        client.send(did_change("file:///Program.cs")).await;
+        assert_eq!(server.receive().await["method"], "textDocument/didChange");
+        let pull = server.receive().await;
+        server
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": pull["id"],
+                "result": {"kind": "full", "resultId": "2", "items": [{"message": "} expected"}]}
+            }))
+            .await;
+
+        // Nothing was published for the unchanged report
+        assert_eq!(
+            client.receive().await["params"]["diagnostics"][0]["message"],
+            "} expected"
+        );
+    }
+
+    #[tokio::test]
+    async fn cancels_outdated_pull() {
+        let (mut client, mut server, _) = start(Proxy::new().with(PushDiagnostics::new()));
+
+        client.send(initialize()).await;
+        client.send(did_open("file:///Program.cs")).await;
+        assert_eq!(server.receive().await["method"], "initialize");
+        assert_eq!(server.receive().await["method"], "textDocument/didOpen");
+
+        let outdated = server.receive().await;
+        assert_eq!(outdated["method"], "textDocument/diagnostic");
+
+        client.send(did_change("file:///Program.cs")).await;
+        assert_eq!(server.receive().await["method"], "textDocument/didChange");
+
+        let cancel = server.receive().await;
+        assert_eq!(cancel["method"], "$/cancelRequest");
+        assert_eq!(cancel["params"]["id"], outdated["id"]);
+
+        let pull = server.receive().await;
+        assert_eq!(pull["method"], "textDocument/diagnostic");
+        assert_ne!(pull["id"], outdated["id"]);
+    }
+
+    #[tokio::test]
+    async fn leaves_pull_clients_alone() {
+        let (mut client, mut server, _) = start(Proxy::new().with(PushDiagnostics::new()));
+
+        let mut pull_client = initialize();
+        pull_client["params"]["capabilities"]["textDocument"]["diagnostic"] = json!({});
+        client.send(pull_client.clone()).await;
+        client.send(did_open("file:///Program.cs")).await;
+
+        assert_eq!(server.receive().await, pull_client);
+        assert_eq!(server.receive().await["method"], "textDocument/didOpen");
+
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}))
+            .await;
+        assert_eq!(server.receive().await["method"], "initialized");
+    }
+}
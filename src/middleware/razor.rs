@@ -0,0 +1,85 @@
+use serde_json::Value;
+
+use crate::{
+    middleware::{Action, Middleware, diagnostic_refresh::document_uri},
+    proxy::Context,
+    transport::Message,
+};
+
+/// The language id the Razor extension of the server handles documents for.
+const RAZOR_LANGUAGE_ID: &str = "aspnetcorerazor";
+
+/// Hands `.razor` and `.cshtml` documents to the Razor extension loaded into the server.
+///
+/// Editors open them with language ids like `razor` or `cshtml`, while the server only treats
+/// documents opened as `aspnetcorerazor` as Razor documents.
+#[derive(Default)]
+pub struct Razor;
+
+impl Razor {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Middleware for Razor {
+    fn on_client_message(&mut self, message: Message, _ctx: &Context) -> Action {
+        if message.method() != Some("textDocument/didOpen")
+            || !document_uri(&message).is_some_and(is_razor_document)
+        {
+            return Action::Forward(message);
+        }
+
+        let Some(mut json) = message.json().cloned() else {
+            return Action::Forward(message);
+        };
+        if let Some(language_id) = json.pointer_mut("/params/textDocument/languageId") {
+            *language_id = Value::from(RAZOR_LANGUAGE_ID);
+        }
+        Action::Forward(Message::from_value(json))
+    }
+}
+
+fn is_razor_document(uri: &str) -> bool {
+    let path = uri
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(uri)
+        .to_ascii_lowercase();
+    path.ends_with(".razor") || path.ends_with(".cshtml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::{Proxy, test_support::start};
+    use serde_json::json;
+
+    fn did_open(uri: &str, language_id: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": uri, "languageId": language_id, "version": 0, "text": ""}}
+        })
+    }
+
+    #[tokio::test]
+    async fn opens_razor_documents_as_razor() {
+        let (mut client, mut server, _) = start(Proxy::new().with(Razor::new()));
+
+        client
+            .send(did_open("file:///App/Pages/Index.razor", "razor"))
+            .await;
+        client
+            .send(did_open("file:///App/Views/Home.CSHTML", "html"))
+            .await;
+        client
+            .send(did_open("file:///App/Program.cs", "csharp"))
+            .await;
+
+        let language_id = |message: Value| message["params"]["textDocument"]["languageId"].clone();
+        assert_eq!(language_id(server.receive().await), RAZOR_LANGUAGE_ID);
+        assert_eq!(language_id(server.receive().await), RAZOR_LANGUAGE_ID);
+        assert_eq!(language_id(server.receive().await), "csharp");
+    }
+}
@@ -0,0 +1,254 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::{process::Command, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::{
+    middleware::{Action, Middleware},
+    progress::{CREATE_TIMEOUT, WorkDoneProgress, client_supports_work_done_progress},
+    proxy::Context,
+    transport::Message,
+};
+
+const PROJECT_NEEDS_RESTORE: &str = "workspace/_roslyn_projectNeedsRestore";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RestoreParams {
+    project_file_paths: Vec<String>,
+}
+
+/// Runs `dotnet restore` when the server asks for projects to be restored.
+///
+/// Only the VS Code extension answers this request, so without it NuGet dependencies are never
+/// resolved.
+pub struct Restore {
+    work_done_progress: bool,
+    progress_timeout: Duration,
+    running: Arc<Mutex<()>>,
+}
+
+impl Default for Restore {
+    fn default() -> Self {
+        Self {
+            work_done_progress: false,
+            progress_timeout: CREATE_TIMEOUT,
+            running: Arc::default(),
+        }
+    }
+}
+
+impl Restore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long the client gets to create the progress of a restore, which runs without progress
+    /// after that.
+    pub fn progress_timeout(mut self, progress_timeout: Duration) -> Self {
+        self.progress_timeout = progress_timeout;
+        self
+    }
+}
+
+impl Middleware for Restore {
+    fn on_client_message(&mut self, message: Message, _ctx: &Context) -> Action {
+        if message.method() == Some("initialize") {
+            self.work_done_progress = client_supports_work_done_progress(&message);
+        }
+        Action::Forward(message)
+    }
+
+    fn on_server_message(&mut self, message: Message, ctx: &Context) -> Action {
+        if message.method() != Some(PROJECT_NEEDS_RESTORE) {
+            return Action::Forward(message);
+        }
+        let Some(id) = message.id().cloned() else {
+            return Action::Forward(message);
+        };
+
+        let projects = message
+            .params()
+            .cloned()
+            .and_then(|params| serde_json::from_value::<RestoreParams>(params).ok())
+            .map(|params| params.project_file_paths)
+            .unwrap_or_default();
+
+        let ctx = ctx.clone();
+        let running = self.running.clone();
+        let work_done_progress = self.work_done_progress;
+        let progress_timeout = self.progress_timeout;
+        tokio::spawn(async move {
+            // Restoring the same project twice at once makes dotnet fight over the lock files
+            let _running = running.lock().await;
+            let progress =
+                WorkDoneProgress::create_within(&ctx.client, work_done_progress, progress_timeout)
+                    .await;
+            restore_projects(&projects, &ctx, progress).await;
+            ctx.server.send(Message::response(id, Value::Null));
+        });
+
+        Action::Drop
+    }
+}
+
+async fn restore_projects(projects: &[String], ctx: &Context, progress: WorkDoneProgress) {
+    progress.begin("Restoring projects", None);
+
+    let mut failed = vec![];
+    for (index, project) in projects.iter().enumerate() {
+        let name = Path::new(project)
+            .file_name()
+            .map_or(project.clone(), |name| name.to_string_lossy().to_string());
+        let percentage = (index * 100 / projects.len()) as u32;
+        progress.report(&format!("Restoring {name}"), percentage);
+
+        info!(project, "Restoring project");
+        if let Err(e) = restore_project(project).await {
+            warn!(project, "{e:#}");
+            ctx.client.notify(
+                "window/logMessage",
+                json!({"type": 1, "message": format!("{e:#}")}),
+            );
+            failed.push(name);
+        }
+    }
+
+    if failed.is_empty() {
+        progress.end(Some("Restore finished"));
+    } else {
+        let message = format!("Restore failed for {}", failed.join(", "));
+        progress.end(Some(&message));
+        ctx.client
+            .notify("window/showMessage", json!({"type": 1, "message": message}));
+    }
+}
+
+async fn restore_project(project: &str) -> Result<()> {
+    let res = Command::new("dotnet")
+        .arg("restore")
+        .arg(project)
+        .output()
+        .await?;
+
+    anyhow::ensure!(
+        res.status.success(),
+        "dotnet restore {project} failed with exit code: {:?}\nstdout: {}\nstderr: {}",
+        res.status.code(),
+        String::from_utf8_lossy(&res.stdout),
+        String::from_utf8_lossy(&res.stderr)
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::{Proxy, test_support::start};
+
+    #[tokio::test]
+    async fn answers_restore_request() {
+        let (mut client, mut server, _) = start(Proxy::new().with(Restore::new()));
+
+        server
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 3,
+                "method": PROJECT_NEEDS_RESTORE,
+                "params": {"projectFilePaths": []}
+            }))
+            .await;
+
+        let response = server.receive().await;
+        assert_eq!(response["id"], 3);
+        assert_eq!(response["result"], Value::Null);
+
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}))
+            .await;
+        assert_eq!(server.receive().await["method"], "initialized");
+    }
+
+    fn initialize() -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": {"capabilities": {"window": {"workDoneProgress": true}}}
+        })
+    }
+
+    fn restore_request(project: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": PROJECT_NEEDS_RESTORE,
+            "params": {"projectFilePaths": [project]}
+        })
+    }
+
+    #[tokio::test]
+    async fn reports_restore_progress() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let project = tmp.path().join("Missing.csproj");
+        let project = project.to_string_lossy();
+        let (mut client, mut server, _) = start(Proxy::new().with(Restore::new()));
+        client.send(initialize()).await;
+        assert_eq!(server.receive().await["method"], "initialize");
+
+        server.send(restore_request(&project)).await;
+
+        let create = client.receive().await;
+        assert_eq!(create["method"], "window/workDoneProgress/create");
+        client
+            .send(json!({"jsonrpc": "2.0", "id": create["id"], "result": null}))
+            .await;
+
+        let mut progress = vec![];
+        let mut shown = None;
+        while shown.is_none() {
+            let message = client.receive().await;
+            match message["method"].as_str() {
+                Some("$/progress") => progress.push(message["params"]["value"].clone()),
+                Some("window/showMessage") => shown = Some(message),
+                _ => {}
+            }
+        }
+        assert_eq!(progress[0]["title"], "Restoring projects");
+        assert_eq!(progress[1]["message"], "Restoring Missing.csproj");
+        assert_eq!(progress[2]["kind"], "end");
+        assert_eq!(
+            shown.unwrap()["params"]["message"],
+            "Restore failed for Missing.csproj"
+        );
+
+        let response = server.receive().await;
+        assert_eq!(response["id"], 3);
+    }
+
+    #[tokio::test]
+    async fn restores_when_client_never_creates_progress() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let project = tmp.path().join("Missing.csproj");
+        let (mut client, mut server, _) =
+            start(Proxy::new().with(Restore::new().progress_timeout(Duration::from_millis(50))));
+        client.send(initialize()).await;
+        assert_eq!(server.receive().await["method"], "initialize");
+
+        server
+            .send(restore_request(&project.to_string_lossy()))
+            .await;
+        assert_eq!(
+            client.receive().await["method"],
+            "window/workDoneProgress/create"
+        );
+
+        let response = server.receive().await;
+        assert_eq!(response["id"], 3);
+        assert_eq!(response["result"], Value::Null);
+    }
+}
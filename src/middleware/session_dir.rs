@@ -0,0 +1,143 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::path::file_uri;
+
+/// A directory for the files a middleware hands to the client, removed when dropped.
+///
+/// The directory is always created by this process, only accessible to the user on unix, so
+/// other users cannot put files or links in it, and a stale directory is never reused.
+pub(crate) struct SessionDir {
+    path: PathBuf,
+    /// The last segment of the URI of the directory
+    uri_name: String,
+}
+
+impl SessionDir {
+    /// A randomly named directory in the temporary directory, like
+    /// `csharp-language-server-metadata-Xa3k9f`.
+    pub(crate) fn new(name: &str) -> Result<Self> {
+        let prefix = format!("csharp-language-server-{name}-");
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&prefix);
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o700));
+        let dir = builder
+            .tempdir()
+            .context("Unable to create a temporary directory")?;
+        Self::with_path(dir.keep())
+    }
+
+    /// The directory at `path`, which must not exist yet.
+    pub(crate) fn at(path: PathBuf) -> Result<Self> {
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(&path)
+            .with_context(|| format!("Unable to create {}", path.display()))?;
+        Self::with_path(path)
+    }
+
+    fn with_path(path: PathBuf) -> Result<Self> {
+        // Removed again when the URI cannot be made
+        let mut dir = Self {
+            path,
+            uri_name: String::new(),
+        };
+        let uri = file_uri(&dir.path)?;
+        dir.uri_name = uri.rsplit('/').next().unwrap_or_default().to_string();
+        Ok(dir)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The name of the directory as it appears in the URIs of its files, to skip messages that
+    /// cannot refer to them without parsing them.
+    pub(crate) fn uri_name(&self) -> &[u8] {
+        self.uri_name.as_bytes()
+    }
+}
+
+impl Drop for SessionDir {
+    fn drop(&mut self) {
+        // Read-only files cannot be removed on Windows
+        make_writable(&self.path);
+        _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn make_writable(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        if path.is_dir() {
+            make_writable(&path);
+        } else {
+            _ = set_readonly(&path, false);
+        }
+    }
+}
+
+pub(crate) fn set_readonly(path: &Path, readonly: bool) -> Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(readonly);
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+/// A directory name unique to the URI, so documents with the same name do not collide.
+pub(crate) fn short_hash(uri: &str) -> String {
+    format!("{:x}", Sha256::digest(uri.as_bytes()))[..16].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn removes_read_only_files() {
+        let dir = SessionDir::new("test").unwrap();
+        let path = dir.path().to_path_buf();
+        let file = path.join(short_hash("file:///A.cs")).join("A.cs");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "class A {}").unwrap();
+        set_readonly(&file, true).unwrap();
+
+        drop(dir);
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn creates_private_directories_only() {
+        let tmp = TempDir::new().unwrap();
+        let existing = tmp.path().join("metadata");
+        fs::create_dir(&existing).unwrap();
+        assert!(SessionDir::at(existing).is_err());
+
+        let first = SessionDir::new("test").unwrap();
+        let second = SessionDir::new("test").unwrap();
+        assert_ne!(first.path(), second.path());
+        assert_eq!(
+            first.uri_name(),
+            first.path().file_name().unwrap().as_encoded_bytes()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(first.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+    }
+}
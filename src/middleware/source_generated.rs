@@ -0,0 +1,448 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context as _, Result};
+use percent_encoding::percent_decode_str;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::{
+    middleware::{
+        Action, Middleware, contains, replace_strings,
+        session_dir::{SessionDir, short_hash},
+    },
+    path::file_uri,
+    proxy::Context,
+    transport::Message,
+};
+
+const SCHEME: &str = "roslyn-source-generated://";
+const GET_TEXT: &str = "sourceGeneratedDocument/_roslyn_getText";
+const REFRESH: &str = "workspace/refreshSourceGeneratedDocument";
+
+/// Turns source-generated documents into files the client can open.
+///
+/// The server refers to the output of source generators with `roslyn-source-generated://` URIs.
+/// Before a response containing such a URI is forwarded, the text of the document is fetched with
+/// `sourceGeneratedDocument/_roslyn_getText` and written to a file in a private directory of the
+/// session, and the URI is replaced with the URI of the file. Notifications and requests of the
+/// server are not held back, to keep their order, and only get the files written so far. Client
+/// messages referring to a file are sent to the server with the original URI, and the files are
+/// updated whenever the generators have run again.
+pub struct SourceGenerated {
+    dir: SessionDir,
+    documents: Arc<Mutex<Documents>>,
+}
+
+#[derive(Default)]
+struct Documents {
+    /// Files by the URI of the server
+    files: HashMap<String, GeneratedFile>,
+    /// URIs of the server by the URI of the file
+    generated_uris: HashMap<String, String>,
+}
+
+struct GeneratedFile {
+    path: PathBuf,
+    uri: String,
+    result_id: Value,
+}
+
+impl SourceGenerated {
+    /// Writes the files to a new private directory in the temporary directory.
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            dir: SessionDir::new("source-generated")?,
+            documents: Arc::default(),
+        })
+    }
+
+    /// Writes the files below `dir`, which must not exist yet and is removed with the middleware.
+    pub fn at(dir: PathBuf) -> Result<Self> {
+        Ok(Self {
+            dir: SessionDir::at(dir)?,
+            documents: Arc::default(),
+        })
+    }
+}
+
+impl Middleware for SourceGenerated {
+    fn on_client_message(&mut self, message: Message, _ctx: &Context) -> Action {
+        if !contains(message.body(), self.dir.uri_name()) {
+            return Action::Forward(message);
+        }
+        let Some(mut json) = message.json().cloned() else {
+            return Action::Forward(message);
+        };
+
+        let replaced = replace_strings(&mut json, &mut |uri| {
+            self.documents
+                .lock()
+                .unwrap()
+                .generated_uris
+                .get(uri)
+                .cloned()
+        });
+        if replaced {
+            Action::Forward(Message::from_value(json))
+        } else {
+            Action::Forward(message)
+        }
+    }
+
+    fn on_server_message(&mut self, message: Message, ctx: &Context) -> Action {
+        if message.method() == Some(REFRESH) {
+            let ctx = ctx.clone();
+            let documents = self.documents.clone();
+            tokio::spawn(async move { refresh(&ctx, &documents).await });
+            return Action::Drop;
+        }
+
+        if !contains(message.body(), SCHEME.as_bytes()) {
+            return Action::Forward(message);
+        }
+        let Some(mut json) = message.json().cloned() else {
+            return Action::Forward(message);
+        };
+
+        if !message.is_response() {
+            let mut unknown = HashSet::new();
+            let replaced = {
+                let documents = self.documents.lock().unwrap();
+                replace_strings(&mut json, &mut |uri| {
+                    let file = documents.files.get(uri);
+                    if file.is_none() && uri.starts_with(SCHEME) {
+                        unknown.insert(uri.to_string());
+                    }
+                    file.map(|file| file.uri.clone())
+                })
+            };
+            // Written for the messages to come
+            for uri in unknown {
+                let ctx = ctx.clone();
+                let documents = self.documents.clone();
+                let dir = self.dir.path().to_path_buf();
+                tokio::spawn(async move {
+                    if let Err(e) = materialize(&ctx, &documents, &dir, &uri).await {
+                        warn!(uri, "Unable to write source-generated document: {e:#}");
+                    }
+                });
+            }
+            return if replaced {
+                Action::Forward(Message::from_value(json))
+            } else {
+                Action::Forward(message)
+            };
+        }
+
+        let ctx = ctx.clone();
+        let documents = self.documents.clone();
+        let dir = self.dir.path().to_path_buf();
+        tokio::spawn(async move {
+            let mut generated_uris = HashSet::new();
+            replace_strings(&mut json, &mut |value| {
+                if value.starts_with(SCHEME) {
+                    generated_uris.insert(value.to_string());
+                }
+                None
+            });
+
+            for uri in generated_uris {
+                if let Err(e) = materialize(&ctx, &documents, &dir, &uri).await {
+                    warn!(uri, "Unable to write source-generated document: {e:#}");
+                }
+            }
+
+            let documents = documents.lock().unwrap();
+            replace_strings(&mut json, &mut |uri| {
+                documents.files.get(uri).map(|file| file.uri.clone())
+            });
+            ctx.client.send(Message::from_value(json));
+        });
+        Action::Drop
+    }
+
+    fn on_server_restart(&mut self, _ctx: &Context) {
+        *self.documents.lock().unwrap() = Documents::default();
+    }
+}
+
+/// Writes a source-generated document to its file, unless it is written already.
+async fn materialize(
+    ctx: &Context,
+    documents: &Mutex<Documents>,
+    dir: &Path,
+    uri: &str,
+) -> Result<()> {
+    if documents.lock().unwrap().files.contains_key(uri) {
+        return Ok(());
+    }
+
+    let path = dir.join(short_hash(uri)).join(file_name(uri));
+    let result = get_text(ctx, uri, &Value::Null).await?;
+    let text = result
+        .get("text")
+        .and_then(Value::as_str)
+        .with_context(|| format!("The server has no text for {uri}"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, text)?;
+
+    let file = GeneratedFile {
+        uri: file_uri(&path)?,
+        path,
+        result_id: result.get("resultId").cloned().unwrap_or_default(),
+    };
+    let mut documents = documents.lock().unwrap();
+    documents
+        .generated_uris
+        .insert(file.uri.clone(), uri.to_string());
+    documents.files.insert(uri.to_string(), file);
+    Ok(())
+}
+
+/// Fetches the text of every written document again, and updates the files that changed.
+async fn refresh(ctx: &Context, documents: &Mutex<Documents>) {
+    let known: Vec<(String, Value)> = documents
+        .lock()
+        .unwrap()
+        .files
+        .iter()
+        .map(|(uri, file)| (uri.clone(), file.result_id.clone()))
+        .collect();
+
+    for (uri, result_id) in known {
+        let result = match get_text(ctx, &uri, &result_id).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!(uri, "Unable to refresh source-generated document: {e:#}");
+                continue;
+            }
+        };
+        // The text is left out when it did not change since the result id
+        let Some(text) = result.get("text").and_then(Value::as_str) else {
+            continue;
+        };
+
+        let mut documents = documents.lock().unwrap();
+        let Some(file) = documents.files.get_mut(&uri) else {
+            continue;
+        };
+        match fs::write(&file.path, text) {
+            Ok(()) => info!(path = %file.path.display(), "Updated source-generated document"),
+            Err(e) => {
+                warn!(path = %file.path.display(), "Unable to update source-generated document: {e}")
+            }
+        }
+        file.result_id = result.get("resultId").cloned().unwrap_or_default();
+    }
+}
+
+async fn get_text(ctx: &Context, uri: &str, result_id: &Value) -> Result<Value> {
+    ctx.server
+        .request(
+            GET_TEXT,
+            json!({"textDocument": {"uri": uri}, "resultId": result_id}),
+        )
+        .await
+}
+
+/// The last segment of the URI path, e.g. `Generated.g.cs` for
+/// `roslyn-source-generated://1234/Generated.g.cs?assemblyName=App`.
+fn file_name(uri: &str) -> String {
+    let path = uri.split(['?', '#']).next().unwrap_or(uri);
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let name = percent_decode_str(name).decode_utf8_lossy();
+    let name: String = name
+        .chars()
+        .map(|c| if r#"\/:*?"<>|"#.contains(c) { '_' } else { c })
+        .collect();
+    if name.is_empty() {
+        "Generated.cs".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        middleware::test_support::{answer_definition, hover_uri},
+        proxy::{
+            Proxy,
+            test_support::{start, start_with_servers},
+        },
+    };
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const GENERATED_URI: &str =
+        "roslyn-source-generated://4a1b/Generated%20Code.g.cs?assemblyName=App&hintName=Code";
+
+    #[test]
+    fn names_files_after_generated_documents() {
+        assert_eq!(file_name(GENERATED_URI), "Generated Code.g.cs");
+        assert_eq!(file_name("roslyn-source-generated://4a1b/"), "Generated.cs");
+        assert_ne!(
+            short_hash(GENERATED_URI),
+            short_hash("roslyn-source-generated://x/a.cs")
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_generated_documents_to_files() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path().join("source-generated");
+        let (mut client, mut server, _) =
+            start(Proxy::new().with(SourceGenerated::at(dir.clone()).unwrap()));
+
+        answer_definition(
+            &mut client,
+            &mut server,
+            1,
+            json!([{"uri": GENERATED_URI, "range": {}}]),
+        )
+        .await;
+
+        let get_text = server.receive().await;
+        assert_eq!(get_text["method"], GET_TEXT);
+        assert_eq!(get_text["params"]["textDocument"]["uri"], GENERATED_URI);
+        server
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": get_text["id"],
+                "result": {"text": "class Code {}", "resultId": "1"}
+            }))
+            .await;
+
+        let definition = client.receive().await;
+        assert_eq!(definition["id"], 1);
+        let file = file_path_of(&definition["result"][0]["uri"]);
+        assert!(file.starts_with(&dir));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "class Code {}");
+
+        assert_eq!(
+            hover_uri(&mut client, &mut server, 2, &definition["result"][0]["uri"]).await,
+            GENERATED_URI
+        );
+
+        server
+            .send(json!({"jsonrpc": "2.0", "method": REFRESH, "params": null}))
+            .await;
+        let get_text = server.receive().await;
+        assert_eq!(get_text["method"], GET_TEXT);
+        assert_eq!(get_text["params"]["resultId"], "1");
+        server
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": get_text["id"],
+                "result": {"text": "class Code { int X; }", "resultId": "2"}
+            }))
+            .await;
+
+        for _ in 0..100 {
+            if fs::read_to_string(&file).unwrap() == "class Code { int X; }" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(fs::read_to_string(&file).unwrap(), "class Code { int X; }");
+    }
+
+    #[tokio::test]
+    async fn keeps_order_of_notifications() {
+        let tmp = TempDir::new().unwrap();
+        let (mut client, mut server, _) =
+            start(Proxy::new().with(SourceGenerated::at(tmp.path().join("generated")).unwrap()));
+        let diagnostics = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": GENERATED_URI, "diagnostics": []}
+        });
+
+        server.send(diagnostics.clone()).await;
+        server
+            .send(json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {}}))
+            .await;
+
+        assert_eq!(client.receive().await["params"]["uri"], GENERATED_URI);
+        assert_eq!(client.receive().await["method"], "window/logMessage");
+        let get_text = server.receive().await;
+        assert_eq!(get_text["method"], GET_TEXT);
+        server
+            .send(json!({"jsonrpc": "2.0", "id": get_text["id"], "result": {"text": ""}}))
+            .await;
+
+        for _ in 0..100 {
+            server.send(diagnostics.clone()).await;
+            if client.receive().await["params"]["uri"] != GENERATED_URI {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("The document was never written");
+    }
+
+    #[tokio::test]
+    async fn forgets_documents_when_server_restarts() {
+        let tmp = TempDir::new().unwrap();
+        let (mut client, mut servers, _) = start_with_servers(
+            Proxy::new()
+                .restart_server(1)
+                .with(SourceGenerated::at(tmp.path().join("generated")).unwrap()),
+        );
+        let mut server = servers.recv().await.unwrap();
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {}}))
+            .await;
+        assert_eq!(server.receive().await["method"], "initialize");
+        server
+            .send(json!({"jsonrpc": "2.0", "id": 0, "result": {}}))
+            .await;
+        assert_eq!(client.receive().await["id"], 0);
+
+        answer_definition(&mut client, &mut server, 1, json!({"uri": GENERATED_URI})).await;
+        let get_text = server.receive().await;
+        server
+            .send(json!({"jsonrpc": "2.0", "id": get_text["id"], "result": {"text": ""}}))
+            .await;
+        let file_uri = client.receive().await["result"]["uri"].clone();
+
+        drop(server);
+        assert_eq!(client.receive().await["method"], "window/showMessage");
+        let mut server = servers.recv().await.unwrap();
+        let initialize = server.receive().await;
+        server
+            .send(json!({"jsonrpc": "2.0", "id": initialize["id"], "result": {}}))
+            .await;
+
+        assert_eq!(
+            hover_uri(&mut client, &mut server, 2, &file_uri).await,
+            file_uri
+        );
+    }
+
+    #[test]
+    fn writes_to_directory_of_its_own() {
+        let tmp = TempDir::new().unwrap();
+        assert!(SourceGenerated::at(tmp.path().to_path_buf()).is_err());
+
+        let first = SourceGenerated::new().unwrap();
+        let second = SourceGenerated::new().unwrap();
+        let dir = first.dir.path().to_path_buf();
+        assert_ne!(dir, second.dir.path());
+
+        drop(first);
+        assert!(!dir.exists());
+    }
+
+    fn file_path_of(uri: &Value) -> PathBuf {
+        crate::path::file_path(uri.as_str().unwrap()).unwrap()
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{
+    config::{Config, find_workspace_config, user_config_path},
+    middleware::{Action, Middleware},
+    proxy::Context,
+    settings::Settings,
+    transport::Message,
+};
+
+/// How often the configuration files are checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Answers the `workspace/configuration` requests of the server from the configuration files.
+///
+/// Clients supporting `workspace/configuration` are asked as well, and every section they answer
+/// with a value takes precedence over the configured one. When the configuration files of the
+/// workspace change, the server is told with `workspace/didChangeConfiguration` and asks again.
+pub struct WorkspaceConfiguration {
+    settings: Arc<Mutex<Settings>>,
+    client_support: bool,
+    watched_dir: Option<PathBuf>,
+    user_config: Option<PathBuf>,
+    poll_interval: Duration,
+}
+
+impl WorkspaceConfiguration {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            settings: Arc::new(Mutex::new(settings)),
+            client_support: false,
+            watched_dir: None,
+            user_config: user_config_path(),
+            poll_interval: POLL_INTERVAL,
+        }
+    }
+
+    /// Reloads the settings when the user configuration or the workspace configuration of the
+    /// directory changes.
+    pub fn watch(mut self, workspace_dir: PathBuf) -> Self {
+        self.watched_dir = Some(workspace_dir);
+        self
+    }
+
+    /// Reads the user configuration from `path` instead of the user configuration directory.
+    pub fn user_config(mut self, path: PathBuf) -> Self {
+        self.user_config = Some(path);
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+}
+
+impl Middleware for WorkspaceConfiguration {
+    fn on_client_message(&mut self, message: Message, ctx: &Context) -> Action {
+        match message.method() {
+            Some("initialize") => {
+                self.client_support = message
+                    .params()
+                    .and_then(|p| p.pointer("/capabilities/workspace/configuration"))
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
+            }
+            Some("initialized") => {
+                if let Some(dir) = self.watched_dir.take() {
+                    let files = ConfigFiles {
+                        user_config: self.user_config.clone(),
+                        dir,
+                    };
+                    // Taken now, so changes made once the server is initialized are noticed
+                    let seen = files.contents();
+                    let settings = self.settings.clone();
+                    let ctx = ctx.clone();
+                    let poll_interval = self.poll_interval;
+                    tokio::spawn(
+                        async move { watch(files, seen, settings, ctx, poll_interval).await },
+                    );
+                }
+            }
+            _ => {}
+        }
+        Action::Forward(message)
+    }
+
+    fn on_server_message(&mut self, message: Message, ctx: &Context) -> Action {
+        if message.method() != Some("workspace/configuration") {
+            return Action::Forward(message);
+        }
+        let Some(id) = message.id().cloned() else {
+            return Action::Forward(message);
+        };
+
+        let params = message.params().cloned().unwrap_or(Value::Null);
+        let settings = self.settings.clone();
+        let client_support = self.client_support;
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let answered = if client_support {
+                ctx.client
+                    .request("workspace/configuration", params.clone())
+                    .await
+                    .inspect_err(|e| {
+                        warn!("The client did not answer workspace/configuration: {e:#}")
+                    })
+                    .unwrap_or_default()
+            } else {
+                Value::Null
+            };
+            let settings = settings.lock().unwrap().clone();
+            let result = answer(&params, &answered, &settings);
+            ctx.server.send(Message::response(id, result));
+        });
+
+        Action::Drop
+    }
+}
+
+/// The value of every requested section: the one the client answered, or else the configured one.
+fn answer(params: &Value, answered: &Value, settings: &Settings) -> Value {
+    let items = params
+        .get("items")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let answered = answered.as_array().map(Vec::as_slice).unwrap_or_default();
+
+    let values = items.iter().enumerate().map(|(i, item)| {
+        answered
+            .get(i)
+            .filter(|value| !value.is_null())
+            .cloned()
+            .or_else(|| {
+                item.get("section")
+                    .and_then(Value::as_str)
+                    .and_then(|section| settings.section(section))
+            })
+            .unwrap_or(Value::Null)
+    });
+    Value::Array(values.collect())
+}
+
+/// The configuration files that apply to a directory.
+struct ConfigFiles {
+    user_config: Option<PathBuf>,
+    dir: PathBuf,
+}
+
+/// The size and SHA-256 of a configuration file, if it can be read.
+type Contents = Option<(usize, Vec<u8>)>;
+
+impl ConfigFiles {
+    /// The contents of the files, compared rather than modification times, which may not change
+    /// when a file is written twice in quick succession.
+    fn contents(&self) -> Vec<(PathBuf, Contents)> {
+        [self.user_config.clone(), find_workspace_config(&self.dir)]
+            .into_iter()
+            .flatten()
+            .map(|path| {
+                let contents = fs::read(&path)
+                    .ok()
+                    .map(|content| (content.len(), Sha256::digest(&content).to_vec()));
+                (path, contents)
+            })
+            .collect()
+    }
+}
+
+async fn watch(
+    files: ConfigFiles,
+    mut seen: Vec<(PathBuf, Contents)>,
+    settings: Arc<Mutex<Settings>>,
+    ctx: Context,
+    poll_interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(poll_interval).await;
+        let current = files.contents();
+        if current == seen {
+            continue;
+        }
+        seen = current;
+
+        let reloaded = match Config::load_with_user_config(
+            Config::default(),
+            files.user_config.as_deref(),
+            &files.dir,
+        ) {
+            Ok(config) => config.settings,
+            Err(e) => {
+                warn!("Unable to reload the configuration: {e:#}");
+                continue;
+            }
+        };
+        let changed = {
+            let mut settings = settings.lock().unwrap();
+            let changed = *settings != reloaded;
+            *settings = reloaded;
+            changed
+        };
+        if changed {
+            info!("Settings changed");
+            ctx.server.notify(
+                "workspace/didChangeConfiguration",
+                json!({"settings": null}),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::WORKSPACE_CONFIG_FILE,
+        proxy::{Proxy, test_support::start},
+    };
+    use tempfile::TempDir;
+
+    fn initialize(configuration: bool) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": {"capabilities": {"workspace": {"configuration": configuration}}}
+        })
+    }
+
+    fn configuration_request() -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "workspace/configuration",
+            "params": {"items": [
+                {"section": "csharp|code_style.formatting.indentation_and_spacing.tab_width"},
+                {"section": "csharp|inlay_hints.csharp_enable_inlay_hints_for_types"},
+                {"section": "csharp|inlay_hints.dotnet_enable_inlay_hints_for_parameters"}
+            ]}
+        })
+    }
+
+    fn settings() -> Settings {
+        let mut settings = Settings::default();
+        settings
+            .code_style
+            .formatting
+            .indentation_and_spacing
+            .tab_width = Some(2);
+        settings.inlay_hints.csharp_enable_inlay_hints_for_types = Some(true);
+        settings
+    }
+
+    #[tokio::test]
+    async fn answers_from_settings() {
+        let (mut client, mut server, _) =
+            start(Proxy::new().with(WorkspaceConfiguration::new(settings())));
+
+        client.send(initialize(false)).await;
+        assert_eq!(server.receive().await["method"], "initialize");
+
+        server.send(configuration_request()).await;
+
+        let response = server.receive().await;
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["result"], json!([2, true, null]));
+    }
+
+    #[tokio::test]
+    async fn merges_client_settings_on_top() {
+        let (mut client, mut server, _) =
+            start(Proxy::new().with(WorkspaceConfiguration::new(settings())));
+
+        client.send(initialize(true)).await;
+        assert_eq!(server.receive().await["method"], "initialize");
+
+        server.send(configuration_request()).await;
+
+        let request = client.receive().await;
+        assert_eq!(request["method"], "workspace/configuration");
+        assert_eq!(request["params"], configuration_request()["params"]);
+        client
+            .send(json!({"jsonrpc": "2.0", "id": request["id"], "result": [4, null, false]}))
+            .await;
+
+        let response = server.receive().await;
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["result"], json!([4, true, false]));
+    }
+
+    #[tokio::test]
+    async fn notifies_server_when_configuration_changes() {
+        let tmp = TempDir::new().unwrap();
+        let user_config = tmp.path().join("config.toml");
+        fs::write(&user_config, "").unwrap();
+        let workspace = tmp.path().join("workspace");
+        fs::create_dir(&workspace).unwrap();
+        let config = workspace.join(WORKSPACE_CONFIG_FILE);
+        fs::write(&config, "").unwrap();
+        let (mut client, mut server, _) = start(
+            Proxy::new().with(
+                WorkspaceConfiguration::new(Settings::default())
+                    .watch(workspace)
+                    .user_config(user_config.clone())
+                    .poll_interval(Duration::from_millis(10)),
+            ),
+        );
+
+        client.send(initialize(false)).await;
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}))
+            .await;
+        assert_eq!(server.receive().await["method"], "initialize");
+        assert_eq!(server.receive().await["method"], "initialized");
+
+        fs::write(
+            &config,
+            "[settings.code_style.formatting.indentation_and_spacing]\ntab_width = 8\n",
+        )
+        .unwrap();
+
+        let notification = server.receive().await;
+        assert_eq!(notification["method"], "workspace/didChangeConfiguration");
+        server.send(configuration_request()).await;
+        assert_eq!(server.receive().await["result"], json!([8, null, null]));
+
+        fs::write(
+            &user_config,
+            "[settings.inlay_hints]\ncsharp_enable_inlay_hints_for_types = true\n",
+        )
+        .unwrap();
+
+        let notification = server.receive().await;
+        assert_eq!(notification["method"], "workspace/didChangeConfiguration");
+        server.send(configuration_request()).await;
+        assert_eq!(server.receive().await["result"], json!([8, true, null]));
+    }
+}
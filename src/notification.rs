@@ -1,5 +1,7 @@
 use serde::Serialize;
 
+use crate::{error::Error, transport::Message};
+
 #[derive(Serialize, Debug)]
 #[serde(untagged)]
 pub enum Params {
@@ -24,16 +26,10 @@ pub struct ProjectParams {
     pub projects: Vec<String>,
 }
 
-impl Notification {
-    pub fn serialize(self) -> String {
-        let body = serde_json::to_string(&self).expect("Unable to serialize notification");
-        add_content_length_header(&body)
-    }
-}
+impl TryFrom<Notification> for Message {
+    type Error = Error;
 
-fn add_content_length_header(body: &str) -> String {
-    let header = format!("Content-Length: {}\r\n\r\n", body.len());
-    let full_message = format!("{header}{body}");
-
-    full_message
+    fn try_from(notification: Notification) -> Result<Self, Error> {
+        Ok(Message::from_body(serde_json::to_vec(&notification)?))
+    }
 }
@@ -0,0 +1,547 @@
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Component, Path},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+
+/// The feed hosting `Microsoft.CodeAnalysis.LanguageServer`.
+pub const DEFAULT_FEED: &str =
+    "https://pkgs.dev.azure.com/azure-public/vside/_packaging/vs-impl/nuget/v3/index.json";
+
+const PACKAGE_BASE_ADDRESS: &str = "PackageBaseAddress/3.0.0";
+
+/// How long connecting to a feed may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a feed may take to start answering.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a request may take in total, long enough to download the server on a slow connection.
+const GLOBAL_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// The agent of every request to the feeds, so an unresponsive feed fails instead of hanging the
+/// installation.
+static AGENT: LazyLock<ureq::Agent> = LazyLock::new(|| {
+    ureq::Agent::config_builder()
+        .timeout_connect(Some(CONNECT_TIMEOUT))
+        .timeout_recv_response(Some(RESPONSE_TIMEOUT))
+        .timeout_global(Some(GLOBAL_TIMEOUT))
+        .build()
+        .into()
+});
+
+#[derive(Deserialize)]
+struct ServiceIndex {
+    resources: Vec<Resource>,
+}
+
+#[derive(Deserialize)]
+struct Resource {
+    #[serde(rename = "@id")]
+    id: String,
+    #[serde(rename = "@type")]
+    resource_type: String,
+}
+
+/// Downloads a package to `destination`, trying the feeds in order.
+///
+/// `on_progress` is called with the number of bytes downloaded so far and the size of the
+/// package, when the feed reports it.
+pub fn download_package(
+    feeds: &[String],
+    package_id: &str,
+    version: &str,
+    destination: &Path,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<()> {
+    let mut errors = vec![];
+    for feed in feeds {
+        match download_from_feed(feed, package_id, version, destination, on_progress) {
+            Ok(()) => return Ok(()),
+            Err(e) => errors.push(format!("{feed}: {e:#}")),
+        }
+    }
+
+    Err(anyhow!(
+        "Unable to download {package_id} {version}\n{}",
+        errors.join("\n")
+    ))
+}
+
+fn download_from_feed(
+    feed: &str,
+    package_id: &str,
+    version: &str,
+    destination: &Path,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<()> {
+    let base_address = package_base_address(feed)?;
+    let id = package_id.to_lowercase();
+    let version = version.to_lowercase();
+    let url = format!(
+        "{}/{id}/{version}/{id}.{version}.nupkg",
+        base_address.trim_end_matches('/')
+    );
+
+    let mut response = AGENT
+        .get(&url)
+        .call()
+        .with_context(|| format!("Unable to download {url}"))?;
+    let size = response.body().content_length();
+    let mut reader = response.body_mut().as_reader();
+    let mut file = File::create(destination)?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut downloaded = 0;
+    loop {
+        let read = reader
+            .read(&mut buffer)
+            .with_context(|| format!("Unable to download {url}"))?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..read])?;
+        downloaded += read as u64;
+        if let Some(size) = size {
+            on_progress(downloaded, size);
+        }
+    }
+
+    Ok(())
+}
+
+/// All versions of a package on the first feed that can be reached, oldest first.
+pub fn package_versions(feeds: &[String], package_id: &str) -> Result<Vec<String>> {
+    let mut errors = vec![];
+    for feed in feeds {
+        match versions_on_feed(feed, package_id) {
+            Ok(versions) => return Ok(versions),
+            Err(e) => errors.push(format!("{feed}: {e:#}")),
+        }
+    }
+
+    Err(anyhow!(
+        "Unable to find versions of {package_id}\n{}",
+        errors.join("\n")
+    ))
+}
+
+#[derive(Deserialize)]
+struct PackageVersions {
+    versions: Vec<String>,
+}
+
+fn versions_on_feed(feed: &str, package_id: &str) -> Result<Vec<String>> {
+    let base_address = package_base_address(feed)?;
+    let url = format!(
+        "{}/{}/index.json",
+        base_address.trim_end_matches('/'),
+        package_id.to_lowercase()
+    );
+
+    let mut versions = AGENT
+        .get(&url)
+        .call()
+        .with_context(|| format!("Unable to read {url}"))?
+        .body_mut()
+        .read_json::<PackageVersions>()
+        .with_context(|| format!("Invalid version list {url}"))?
+        .versions;
+    versions.sort_by(|a, b| compare_versions(a, b));
+
+    Ok(versions)
+}
+
+/// Orders NuGet versions, with pre-releases before the release they lead up to.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn split(version: &str) -> (&str, Option<&str>) {
+        let version = version.split('+').next().unwrap_or(version);
+        match version.split_once('-') {
+            Some((release, prerelease)) => (release, Some(prerelease)),
+            None => (version, None),
+        }
+    }
+
+    fn compare_parts(a: &str, b: &str, separator: char) -> Ordering {
+        let mut a = a.split(separator);
+        let mut b = b.split(separator);
+        loop {
+            match (a.next(), b.next()) {
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+                (Some(a), Some(b)) => {
+                    let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+                        (Ok(a), Ok(b)) => a.cmp(&b),
+                        (Ok(_), Err(_)) => Ordering::Less,
+                        (Err(_), Ok(_)) => Ordering::Greater,
+                        (Err(_), Err(_)) => a.to_lowercase().cmp(&b.to_lowercase()),
+                    };
+                    if ordering.is_ne() {
+                        return ordering;
+                    }
+                }
+            }
+        }
+    }
+
+    let (a_release, a_prerelease) = split(a);
+    let (b_release, b_prerelease) = split(b);
+
+    compare_parts(a_release, b_release, '.').then_with(|| match (a_prerelease, b_prerelease) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => compare_parts(a, b, '.'),
+    })
+}
+
+/// Resolves the address packages are downloaded from through the feed's service index.
+fn package_base_address(feed: &str) -> Result<String> {
+    let index: ServiceIndex = AGENT
+        .get(feed)
+        .call()
+        .with_context(|| format!("Unable to read service index {feed}"))?
+        .body_mut()
+        .read_json()
+        .with_context(|| format!("Invalid service index {feed}"))?;
+
+    index
+        .resources
+        .into_iter()
+        .find(|resource| resource.resource_type == PACKAGE_BASE_ADDRESS)
+        .map(|resource| resource.id)
+        .with_context(|| format!("{feed} has no {PACKAGE_BASE_ADDRESS} resource"))
+}
+
+/// Extracts the files below `directory` in a package into `destination`.
+///
+/// `on_progress` is called with the number of package entries processed so far and their total.
+pub fn extract_directory(
+    package: &Path,
+    directory: &str,
+    destination: &Path,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(package)?)
+        .with_context(|| format!("{} is not a valid package", package.display()))?;
+    let prefix = format!("{}/", directory.trim_end_matches('/'));
+
+    let mut extracted = 0;
+    for index in 0..archive.len() {
+        on_progress(index as u64 + 1, archive.len() as u64);
+        let mut entry = archive.by_index(index)?;
+        // Package entries are percent encoded by NuGet when packing
+        let name = percent_decode_str(entry.name())
+            .decode_utf8_lossy()
+            .to_string();
+        let Some(relative) = name.strip_prefix(&prefix) else {
+            continue;
+        };
+
+        let relative = Path::new(relative);
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            bail!("Package entry {name} escapes the package");
+        }
+
+        let target = destination.join(relative);
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut entry, &mut File::create(&target)?)?;
+        set_executable(&target)?;
+        extracted += 1;
+    }
+
+    anyhow::ensure!(
+        extracted > 0,
+        "{} does not contain {directory}",
+        package.display()
+    );
+    Ok(())
+}
+
+/// The id and version declared in the `.nuspec` of a package file or an unpacked package.
+pub fn package_identity(package: &Path) -> Result<(String, String)> {
+    let nuspec = if package.is_dir() {
+        let nuspec = fs::read_dir(package)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| path.extension().is_some_and(|e| e == "nuspec"))
+            .with_context(|| format!("{} has no .nuspec", package.display()))?;
+        fs::read_to_string(nuspec)?
+    } else {
+        let mut archive = zip::ZipArchive::new(File::open(package)?)
+            .with_context(|| format!("{} is not a valid package", package.display()))?;
+        let name = archive
+            .file_names()
+            .find(|name| !name.contains('/') && name.ends_with(".nuspec"))
+            .with_context(|| format!("{} has no .nuspec", package.display()))?
+            .to_string();
+        io::read_to_string(archive.by_name(&name)?)?
+    };
+
+    let id = xml_element(&nuspec, "id");
+    let version = xml_element(&nuspec, "version");
+    id.zip(version)
+        .with_context(|| format!("{} has no package id or version", package.display()))
+}
+
+/// The text of the first `<name>` element.
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let open = format!("<{name}>");
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find('<')?;
+    Some(xml[start..start + end].trim().to_string())
+}
+
+/// Packages are built on Windows and carry no permissions, so native executables have to be
+/// marked as such.
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if path.extension().is_none() {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Cursor, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use zip::write::SimpleFileOptions;
+
+    /// Builds a package containing the given files.
+    pub fn package(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in files {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Serves the given paths over HTTP and returns the address of the server.
+    ///
+    /// The service index is available at `/index.json` and points at `/packages`.
+    pub fn serve(routes: HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+
+        let mut routes = routes;
+        routes.insert(
+            "/index.json".to_string(),
+            serde_json::to_vec(&serde_json::json!({
+                "version": "3.0.0",
+                "resources": [
+                    {"@id": format!("{address}/packages/"), "@type": "PackageBaseAddress/3.0.0"}
+                ]
+            }))
+            .unwrap(),
+        );
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let response = match routes.get(path) {
+                    Some(body) => {
+                        let mut response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        response.extend(body);
+                        response
+                    }
+                    None => {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                _ = stream.write_all(&response);
+            }
+        });
+
+        address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::{package, serve};
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    #[test]
+    fn downloads_and_extracts_package() {
+        let nupkg = package(&[
+            ("Some.Package.nuspec", "<package />"),
+            ("content/LanguageServer/linux-x64/Server.dll", "server"),
+            (
+                "content/LanguageServer/linux-x64/cs/Resources%2B.dll",
+                "resources",
+            ),
+        ]);
+        let address = serve(HashMap::from([(
+            "/packages/some.package/1.0.0-beta/some.package.1.0.0-beta.nupkg".to_string(),
+            nupkg.clone(),
+        )]));
+        let tmp = TempDir::new().unwrap();
+        let downloaded = tmp.path().join("package.nupkg");
+
+        let feeds = vec![
+            format!("{address}/missing/index.json"),
+            format!("{address}/index.json"),
+        ];
+        let mut downloading = vec![];
+        download_package(
+            &feeds,
+            "Some.Package",
+            "1.0.0-Beta",
+            &downloaded,
+            &mut |done, total| downloading.push((done, total)),
+        )
+        .unwrap();
+
+        let out = tmp.path().join("out");
+        let mut extracting = vec![];
+        extract_directory(
+            &downloaded,
+            "content/LanguageServer",
+            &out,
+            &mut |done, total| extracting.push((done, total)),
+        )
+        .unwrap();
+
+        let size = nupkg.len() as u64;
+        assert_eq!(downloading.last(), Some(&(size, size)));
+        assert_eq!(extracting, vec![(1, 3), (2, 3), (3, 3)]);
+
+        assert_eq!(
+            fs::read_to_string(out.join("linux-x64").join("Server.dll")).unwrap(),
+            "server"
+        );
+        assert!(
+            out.join("linux-x64")
+                .join("cs")
+                .join("Resources+.dll")
+                .exists()
+        );
+        assert!(!out.join("Some.Package.nuspec").exists());
+    }
+
+    #[test]
+    fn lists_package_versions() {
+        let address = serve(HashMap::from([(
+            "/packages/some.package/index.json".to_string(),
+            br#"{"versions": ["1.10.0", "1.2.0", "1.10.0-beta.2"]}"#.to_vec(),
+        )]));
+
+        let versions = package_versions(&[format!("{address}/index.json")], "Some.Package");
+
+        assert_eq!(versions.unwrap(), vec!["1.2.0", "1.10.0-beta.2", "1.10.0"]);
+    }
+
+    #[test]
+    fn orders_versions() {
+        assert!(compare_versions("5.4.0-2.26080.13", "5.4.0-2.26100.1").is_lt());
+        assert!(compare_versions("5.4.0-2.26080.13", "5.4.0").is_lt());
+        assert!(compare_versions("5.10.0-1.1", "5.9.0").is_gt());
+        assert!(compare_versions("5.0.0-beta.10", "5.0.0-beta.9").is_gt());
+        assert!(compare_versions("5.0.0", "5.0.0+build").is_eq());
+    }
+
+    #[test]
+    fn fails_for_missing_package() {
+        let address = serve(HashMap::new());
+        let tmp = TempDir::new().unwrap();
+
+        let result = download_package(
+            &[format!("{address}/index.json")],
+            "Some.Package",
+            "1.0.0",
+            &tmp.path().join("package.nupkg"),
+            &mut |_, _| {},
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reads_package_identity() {
+        let tmp = TempDir::new().unwrap();
+        let nuspec = r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://schemas.microsoft.com/packaging/2013/05/nuspec.xsd">
+  <metadata>
+    <id>Some.Package</id>
+    <version>1.0.0-beta</version>
+  </metadata>
+</package>"#;
+        let nupkg = tmp.path().join("package.nupkg");
+        fs::write(&nupkg, package(&[("Some.Package.nuspec", nuspec)])).unwrap();
+        fs::write(tmp.path().join("some.package.nuspec"), nuspec).unwrap();
+
+        let expected = ("Some.Package".to_string(), "1.0.0-beta".to_string());
+        assert_eq!(package_identity(&nupkg).unwrap(), expected);
+        assert_eq!(package_identity(tmp.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_entries_outside_package() {
+        let tmp = TempDir::new().unwrap();
+        let nupkg = tmp.path().join("package.nupkg");
+        fs::write(
+            &nupkg,
+            package(&[("content/LanguageServer/../../evil", "evil")]),
+        )
+        .unwrap();
+
+        let result = extract_directory(
+            &nupkg,
+            "content/LanguageServer",
+            &tmp.path().join("out"),
+            &mut |_, _| {},
+        );
+
+        assert!(result.is_err());
+    }
+}
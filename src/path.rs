@@ -1,51 +1,84 @@
 use serde_json::Value;
 use std::{ffi::OsStr, path::PathBuf};
+use tracing::warn;
 use url::Url;
 
-use anyhow::{Context, Result};
-
+use crate::error::{Error, Result};
 use crate::notification::{Notification, Params, ProjectParams, SolutionParams};
-
-pub fn create_open_notification(
-    notification: &str,
-    solution_override: Option<String>,
-    projects_override: Option<Vec<String>>,
-) -> String {
-    let root_path =
-        parse_root_path(notification).expect("Root path not part of initialize notification");
-
-    let open_solution_notification = open_solution_notification(&root_path, solution_override);
-
-    if let Some(open_solution_notification) = open_solution_notification {
-        return open_solution_notification;
+use crate::transport::Message;
+
+/// The workspace folders given by the client in its `initialize` request.
+///
+/// Falls back to the root path for clients without multi-root support.
+pub fn workspace_folders(initialize: &Message) -> Result<Vec<PathBuf>> {
+    let params = initialize.params().cloned().unwrap_or_default();
+    let folders = folder_paths(&params["workspaceFolders"]);
+    if !folders.is_empty() {
+        return Ok(folders);
     }
 
-    open_projects_notification(&root_path, projects_override)
+    Ok(vec![parse_root_path(&params)?.0])
 }
 
-fn open_solution_notification(root_path: &Path, override_path: Option<String>) -> Option<String> {
-    let solution_path = match override_path {
-        Some(p) => root_path.join(&p),
-        None => find_extension(root_path, &vec![OsStr::new("sln"), OsStr::new("slnx")]).next()?,
-    };
+/// Paths of a list of `WorkspaceFolder`s. Folders that are not files are skipped.
+pub fn folder_paths(folders: &Value) -> Vec<PathBuf> {
+    folders
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|folder| folder["uri"].as_str())
+        .filter_map(|uri| {
+            file_path(uri)
+                .inspect_err(|e| warn!("Skipping workspace folder: {e}"))
+                .ok()
+        })
+        .collect()
+}
 
-    Some(
-        Notification {
-            jsonrpc: "2.0".to_string(),
-            method: "solution/open".to_string(),
-            params: Params::Solution(SolutionParams {
-                solution: solution_path.to_uri_string(),
-            }),
-        }
-        .serialize(),
+/// All `.sln` and `.slnx` files under the root, shallowest first.
+pub fn find_solutions(root_path: &std::path::Path) -> Vec<PathBuf> {
+    find_extension(
+        &root_path.to_path_buf().into(),
+        &vec![OsStr::new("sln"), OsStr::new("slnx")],
     )
+    .map(|p| p.0)
+    .collect()
+}
+
+/// All `.csproj` files under the root, shallowest first.
+pub fn find_projects(root_path: &std::path::Path) -> Vec<PathBuf> {
+    find_extension(&root_path.to_path_buf().into(), &vec![OsStr::new("csproj")])
+        .map(|p| p.0)
+        .collect()
+}
+
+pub fn open_solution_notification(solution_path: &std::path::Path) -> Result<Message> {
+    Notification {
+        jsonrpc: "2.0".to_string(),
+        method: "solution/open".to_string(),
+        params: Params::Solution(SolutionParams {
+            solution: Path(solution_path.to_path_buf()).to_uri_string()?,
+        }),
+    }
+    .try_into()
 }
 
-fn open_projects_notification(root_path: &Path, override_paths: Option<Vec<String>>) -> String {
+/// `project/open` for the overridden projects, or else every project under the root. Projects
+/// whose path cannot be turned into a URI are skipped.
+pub fn open_projects_notification(
+    root_path: &std::path::Path,
+    override_paths: Option<Vec<String>>,
+) -> Result<Message> {
     let file_paths = match override_paths {
         Some(p) => p,
-        None => find_extension(root_path, &vec![OsStr::new("csproj")])
-            .map(|p| p.to_uri_string())
+        None => find_projects(root_path)
+            .into_iter()
+            .filter_map(|p| {
+                Path(p)
+                    .to_uri_string()
+                    .inspect_err(|e| warn!("Skipping project: {e}"))
+                    .ok()
+            })
             .collect(),
     };
 
@@ -57,29 +90,38 @@ fn open_projects_notification(root_path: &Path, override_paths: Option<Vec<Strin
         }),
     };
 
-    notification.serialize()
+    notification.try_into()
+}
+
+/// Turns a document URI from the client into a file path.
+pub fn file_path(uri: &str) -> Result<PathBuf> {
+    Path::try_from_uri(uri).map(|p| p.0)
+}
+
+/// Turns an absolute file path into a document URI for the client.
+pub fn file_uri(path: &std::path::Path) -> Result<String> {
+    Path::from(path.to_path_buf()).to_uri_string()
 }
 
 #[derive(Debug, Clone)]
 struct Path(PathBuf);
 
 impl Path {
-    fn try_from_uri(uri: &str) -> Option<Self> {
+    fn try_from_uri(uri: &str) -> Result<Self> {
         uri.parse::<Url>()
-            .expect("Couldn't parse path URI")
+            .map_err(|source| Error::InvalidUri {
+                uri: uri.to_string(),
+                source,
+            })?
             .to_file_path()
-            .ok()
             .map(Self)
+            .map_err(|_| Error::NotAFileUri(uri.to_string()))
     }
 
-    fn to_uri_string(&self) -> String {
+    fn to_uri_string(&self) -> Result<String> {
         Url::from_file_path(&self.0)
-            .unwrap_or_else(|_| panic!("Couldn't turn {:?} into Uri", self.0))
-            .to_string()
-    }
-
-    fn join(&self, part: &str) -> Self {
-        Path(self.0.join(part.trim()))
+            .map(|url| url.to_string())
+            .map_err(|_| Error::NotAnAbsolutePath(self.0.clone()))
     }
 }
 
@@ -97,30 +139,16 @@ impl From<PathBuf> for Path {
 
 impl From<&ignore::DirEntry> for Path {
     fn from(value: &ignore::DirEntry) -> Self {
-        value.path().to_str().unwrap().into()
+        value.path().to_path_buf().into()
     }
 }
 
-fn parse_root_path(notification: &str) -> Result<Path> {
-    let json_start = notification
-        .find('{')
-        .context("Notification was not json")?;
-
-    let parsed_notification: Value = serde_json::from_str(&notification[json_start..])?;
-
-    let root_path = parsed_notification["params"]["rootUri"]
-        .as_str()
-        .map_or_else(
-            || {
-                parsed_notification["params"]["rootPath"]
-                    .as_str()
-                    .map(|p| p.into())
-            },
-            Path::try_from_uri,
-        )
-        .context("Root URI/path was not given by the client")?;
-
-    Ok(root_path)
+fn parse_root_path(params: &Value) -> Result<Path> {
+    match (params["rootUri"].as_str(), params["rootPath"].as_str()) {
+        (Some(uri), _) => Path::try_from_uri(uri),
+        (None, Some(path)) => Ok(path.into()),
+        (None, None) => Err(Error::NoWorkspace),
+    }
 }
 
 fn path_for_file_with_extension(dir: &ignore::DirEntry, ext: &Vec<&'static OsStr>) -> Option<Path> {
@@ -158,7 +186,7 @@ mod tests {
                 let from_uri =
                     Path::try_from_uri($input);
                     let expected = $expected.replace('/', std::path::MAIN_SEPARATOR_STR);
-                assert!(from_uri.as_ref().is_some_and(|p| p.0.to_str().is_some_and(|s| s.eq(&expected))),
+                assert!(from_uri.as_ref().is_ok_and(|p| p.0.to_str().is_some_and(|s| s.eq(&expected))),
                     "try_from_uri for '{}' was '{:?}' and not '{}'", $input, &from_uri, expected
                 )
             }
@@ -179,6 +207,29 @@ mod tests {
         with_parent_unix: ("file:///var/_Foo/bar/../baz","/var/_Foo/baz"),
     }
 
+    #[test]
+    fn rejects_odd_uris_and_initialize_without_workspace() {
+        assert!(matches!(
+            Path::try_from_uri("not a uri"),
+            Err(Error::InvalidUri { .. })
+        ));
+        assert!(matches!(
+            Path::try_from_uri("untitled:Untitled-1"),
+            Err(Error::NotAFileUri(_))
+        ));
+        assert!(matches!(
+            Path::from("relative/App.sln").to_uri_string(),
+            Err(Error::NotAnAbsolutePath(_))
+        ));
+
+        let initialize =
+            Message::request(serde_json::json!(1), "initialize", serde_json::json!({}));
+        assert!(matches!(
+            workspace_folders(&initialize),
+            Err(Error::NoWorkspace)
+        ));
+    }
+
     fn touch(path: &std::path::Path) {
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent).unwrap();
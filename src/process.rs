@@ -0,0 +1,152 @@
+use std::{
+    process::ExitStatus,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::process::Child;
+use tracing::{info, warn};
+
+/// The running server process, shared between the proxy starting it and the wrapper stopping it.
+#[derive(Clone, Default)]
+pub struct ServerProcess {
+    child: Arc<Mutex<Option<Child>>>,
+}
+
+impl ServerProcess {
+    /// Tracks a newly started server. A previous server that is still running is killed.
+    pub fn replace(&self, child: Child) {
+        if let Some(mut previous) = self.child.lock().unwrap().replace(child) {
+            info!("Killing the previous server");
+            kill_tree(&mut previous);
+        }
+    }
+
+    /// Waits for the server to exit, and kills it with the processes it started when it has not
+    /// exited within `timeout`. Returns `None` when no server was started.
+    pub async fn stop(&self, timeout: Duration) -> Option<ExitStatus> {
+        let mut child = self.child.lock().unwrap().take()?;
+        if let Ok(status) = tokio::time::timeout(timeout, child.wait()).await {
+            return exited(status.ok());
+        }
+
+        warn!("The server did not exit within {timeout:?}, killing it");
+        kill_tree(&mut child);
+        exited(child.wait().await.ok())
+    }
+
+    /// Kills the server with the processes it started.
+    pub async fn kill(&self) -> Option<ExitStatus> {
+        let mut child = self.child.lock().unwrap().take()?;
+        info!("Killing the server");
+        kill_tree(&mut child);
+        exited(child.wait().await.ok())
+    }
+}
+
+fn exited(status: Option<ExitStatus>) -> Option<ExitStatus> {
+    match &status {
+        Some(status) => info!(%status, "Server exited"),
+        None => warn!("Unable to get the exit status of the server"),
+    }
+    status
+}
+
+/// Kills a process started in a process group of its own, with everything else in the group. On
+/// Windows the process tree is killed instead.
+fn kill_tree(child: &mut Child) {
+    if let Some(pid) = child.id() {
+        #[cfg(unix)]
+        // SAFETY: kill has no memory safety requirements
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+
+        #[cfg(windows)]
+        {
+            _ = std::process::Command::new("taskkill")
+                .args(["/T", "/F", "/PID", &pid.to_string()])
+                .output();
+        }
+    }
+    _ = child.start_kill();
+}
+
+/// Resolves when the wrapper is asked to terminate by SIGTERM, SIGINT or SIGHUP, e.g. when the
+/// terminal of the editor is closed, with the exit code to report for it.
+pub async fn termination_signal() -> i32 {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())
+            .inspect_err(|e| warn!("Unable to listen for SIGTERM: {e}"))
+            .ok();
+        let mut hangup = signal(SignalKind::hangup())
+            .inspect_err(|e| warn!("Unable to listen for SIGHUP: {e}"))
+            .ok();
+        tokio::select! {
+            Some(_) = async { terminate.as_mut()?.recv().await } => 128 + libc::SIGTERM,
+            Some(_) = async { hangup.as_mut()?.recv().await } => 128 + libc::SIGHUP,
+            _ = tokio::signal::ctrl_c() => 128 + libc::SIGINT,
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        _ = tokio::signal::ctrl_c().await;
+        130
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::process::Command;
+
+    fn spawn(script: &str) -> Child {
+        Command::new("sh")
+            .args(["-c", script])
+            .process_group(0)
+            .spawn()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn returns_exit_status_of_server() {
+        let process = ServerProcess::default();
+        process.replace(spawn("exit 3"));
+
+        let status = process.stop(Duration::from_secs(5)).await.unwrap();
+
+        assert_eq!(status.code(), Some(3));
+        assert!(process.stop(Duration::ZERO).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn kills_server_not_exiting_in_time() {
+        let process = ServerProcess::default();
+        process.replace(spawn("sleep 30 & wait"));
+
+        let status = process.stop(Duration::from_millis(50)).await.unwrap();
+
+        assert_eq!(status.code(), None);
+    }
+
+    #[tokio::test]
+    async fn terminates_on_hangup() {
+        let terminated = tokio::spawn(termination_signal());
+        // Listening before the signal is raised, so it never reaches the default handler
+        let mut hangup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+        tokio::task::yield_now().await;
+
+        // SAFETY: raise has no memory safety requirements
+        unsafe {
+            libc::raise(libc::SIGHUP);
+        }
+
+        hangup.recv().await;
+        assert_eq!(terminated.await.unwrap(), 128 + libc::SIGHUP);
+    }
+}
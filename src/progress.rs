@@ -0,0 +1,95 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde_json::{Value, json};
+
+use crate::{proxy::Peer, transport::Message};
+
+/// How long the client gets to answer `window/workDoneProgress/create` before the work goes on
+/// without progress.
+pub const CREATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Work done progress reported to the client through `$/progress`.
+///
+/// When the client does not support work done progress, every call is a no-op.
+pub struct WorkDoneProgress {
+    client: Peer,
+    token: Option<Value>,
+}
+
+impl WorkDoneProgress {
+    /// Asks the client to create a new progress token.
+    pub async fn create(client: &Peer, supported: bool) -> Self {
+        Self::create_within(client, supported, CREATE_TIMEOUT).await
+    }
+
+    /// Like [`WorkDoneProgress::create`], giving up on progress when the client does not answer
+    /// within `timeout`.
+    pub async fn create_within(client: &Peer, supported: bool, timeout: Duration) -> Self {
+        let mut token = None;
+        if supported {
+            let new_token = json!(format!(
+                "csharp-language-server/{}",
+                NEXT_TOKEN.fetch_add(1, Ordering::Relaxed)
+            ));
+            let created = tokio::time::timeout(
+                timeout,
+                client.request(
+                    "window/workDoneProgress/create",
+                    json!({"token": new_token}),
+                ),
+            )
+            .await;
+            token = matches!(created, Ok(Ok(_))).then_some(new_token);
+        }
+
+        Self {
+            client: client.clone(),
+            token,
+        }
+    }
+
+    pub fn begin(&self, title: &str, message: Option<&str>) {
+        let mut value = json!({"kind": "begin", "title": title, "percentage": 0});
+        if let Some(message) = message {
+            value["message"] = message.into();
+        }
+        self.send(value);
+    }
+
+    pub fn report(&self, message: &str, percentage: u32) {
+        self.send(json!({
+            "kind": "report",
+            "message": message,
+            "percentage": percentage.min(100),
+        }));
+    }
+
+    pub fn end(self, message: Option<&str>) {
+        let mut value = json!({"kind": "end"});
+        if let Some(message) = message {
+            value["message"] = message.into();
+        }
+        self.send(value);
+    }
+
+    fn send(&self, value: Value) {
+        if let Some(token) = &self.token {
+            self.client
+                .notify("$/progress", json!({"token": token, "value": value}));
+        }
+    }
+}
+
+/// Whether the client declared `window.workDoneProgress` in its `initialize` request.
+pub fn client_supports_work_done_progress(initialize: &Message) -> bool {
+    initialize
+        .params()
+        .and_then(|params| params.pointer("/capabilities/window/workDoneProgress"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
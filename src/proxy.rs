@@ -0,0 +1,952 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufRead, AsyncWrite},
+    sync::{mpsc, oneshot, watch},
+};
+use tracing::{error, warn};
+
+use crate::{
+    middleware::{Action, Middleware},
+    session::{REQUEST_FAILED, Session},
+    trace::{self, Trace},
+    transport::{Message, MessageReader, MessageWriter},
+};
+
+const REQUEST_ID_PREFIX: &str = "csharp-language-server-";
+
+/// Whether the id belongs to a request sent by the proxy itself rather than the client or server.
+pub fn is_proxy_request_id(id: &Value) -> bool {
+    id.as_str()
+        .is_some_and(|id| id.starts_with(REQUEST_ID_PREFIX))
+}
+
+/// One side of the proxied connection, used by middlewares to send messages to it.
+#[derive(Clone)]
+pub struct Peer {
+    sender: mpsc::UnboundedSender<Message>,
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<Message>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Peer {
+    fn new(sender: mpsc::UnboundedSender<Message>, next_id: Arc<AtomicU64>) -> Self {
+        Self {
+            sender,
+            pending: Arc::default(),
+            next_id,
+        }
+    }
+
+    pub fn send(&self, message: Message) {
+        // The receiver only goes away when the proxy is shutting down
+        _ = self.sender.send(message);
+    }
+
+    pub fn notify(&self, method: &str, params: Value) {
+        self.send(Message::notification(method, params));
+    }
+
+    /// Sends a request originating from the proxy and waits for its result.
+    ///
+    /// The response is consumed by the proxy and never reaches the other side. Dropping the
+    /// returned future before it completes sends `$/cancelRequest`.
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_request_id();
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), sender);
+        let cancel_on_drop = CancelOnDrop {
+            peer: self,
+            id: &id,
+        };
+
+        self.send(Message::request(id.clone().into(), method, params));
+
+        let response = receiver
+            .await
+            .with_context(|| format!("Connection closed before {method} was answered"))?;
+        drop(cancel_on_drop);
+        let response = response.json().context("Response was not json")?;
+
+        match response.get("error") {
+            Some(error) => Err(anyhow!("{method} failed: {error}")),
+            None => Ok(response.get("result").cloned().unwrap_or_default()),
+        }
+    }
+
+    fn next_request_id(&self) -> String {
+        format!(
+            "{REQUEST_ID_PREFIX}{}",
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    /// Registers a request the proxy writes itself, and returns its id and the receiver of its
+    /// response, which never reaches the other side.
+    fn expect_response(&self) -> (String, oneshot::Receiver<Message>) {
+        let id = self.next_request_id();
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), sender);
+        (id, receiver)
+    }
+
+    /// Fails the pending proxy requests, which will never be answered.
+    fn fail_pending(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// Hands a response to a pending proxy request. Returns the message if it was not one.
+    fn complete(&self, message: Message) -> Option<Message> {
+        if !message.is_response() {
+            return Some(message);
+        }
+        let Some(id) = message.id().and_then(Value::as_str) else {
+            return Some(message);
+        };
+        let Some(sender) = self.pending.lock().unwrap().remove(id) else {
+            return Some(message);
+        };
+        _ = sender.send(message);
+        None
+    }
+}
+
+/// Cancels a proxy request that is dropped before it has been answered.
+struct CancelOnDrop<'a> {
+    peer: &'a Peer,
+    id: &'a str,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.peer.pending.lock().unwrap().remove(self.id).is_some() {
+            self.peer.notify("$/cancelRequest", json!({"id": self.id}));
+        }
+    }
+}
+
+/// Handles for reaching both sides of the connection.
+#[derive(Clone)]
+pub struct Context {
+    pub client: Peer,
+    pub server: Peer,
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// A server running for longer than this before exiting is restarted without counting towards
+/// the restarts in a row.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// How long the server has to exit once the client has sent `exit` or closed its input.
+pub const DEFAULT_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Forwards framed messages between the client and the server through a chain of middlewares.
+pub struct Proxy {
+    middlewares: Vec<Box<dyn Middleware>>,
+    max_restarts: u32,
+    exit_timeout: Duration,
+    trace: Trace,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self {
+            middlewares: vec![],
+            max_restarts: 0,
+            exit_timeout: DEFAULT_EXIT_TIMEOUT,
+            trace: Trace::default(),
+        }
+    }
+}
+
+impl Proxy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Restarts the server when it exits while the client is still using it, at most
+    /// `max_restarts` times in a row. The new server is told about the workspace and the open
+    /// documents, and the requests the previous server left unanswered fail.
+    pub fn restart_server(mut self, max_restarts: u32) -> Self {
+        self.max_restarts = max_restarts;
+        self
+    }
+
+    /// How long the server has to close its output once the client has sent `exit` or closed its
+    /// input, before the run ends with an error.
+    pub fn exit_timeout(mut self, exit_timeout: Duration) -> Self {
+        self.exit_timeout = exit_timeout;
+        self
+    }
+
+    /// Records every message read from or written to the client and the server.
+    pub fn trace(mut self, trace: Trace) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Runs until the server closes its output.
+    ///
+    /// When the client closes its input, everything already queued is sent to the server before
+    /// the server input is closed.
+    pub async fn run(
+        self,
+        client_reader: impl AsyncBufRead + Unpin,
+        client_writer: impl AsyncWrite + Unpin,
+        server_reader: impl AsyncBufRead + Unpin,
+        server_writer: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        let mut server = Some((server_reader, server_writer));
+        self.run_with_server(client_reader, client_writer, move |_, _| {
+            let server = server.take();
+            async move { server.context("The server cannot be started again") }
+        })
+        .await
+    }
+
+    /// Runs like [`Proxy::run`], with the server started by `start_server`.
+    ///
+    /// `start_server` is given the context, through which it can reach the client, and the
+    /// `initialize` request once the client has sent it. Client messages are dispatched as usual
+    /// while the server starts, and queued until it is connected. If it fails to start, its error
+    /// is sent to the client as the response to `initialize`, or shown to it when restarting, and
+    /// the run ends with it once the messages queued for the client have been sent.
+    ///
+    /// `start_server` is called again for every restart.
+    pub async fn run_with_server<R, W, F>(
+        self,
+        client_reader: impl AsyncBufRead + Unpin,
+        client_writer: impl AsyncWrite + Unpin,
+        mut start_server: impl FnMut(Context, oneshot::Receiver<Message>) -> F,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+        F: Future<Output = Result<(R, W)>>,
+    {
+        let Proxy {
+            middlewares,
+            max_restarts,
+            exit_timeout,
+            trace,
+        } = self;
+        let next_id = Arc::new(AtomicU64::new(0));
+        let (client_sender, mut client_receiver) = mpsc::unbounded_channel();
+        let (server_sender, mut server_receiver) = mpsc::unbounded_channel();
+        let ctx = Context {
+            client: Peer::new(client_sender, next_id.clone()),
+            server: Peer::new(server_sender, next_id),
+        };
+        let middlewares = Mutex::new(middlewares);
+        let session = Mutex::new(Session::default());
+        let (client_closed_sender, client_closed) = watch::channel(false);
+        let (initialize_sender, initialize) = oneshot::channel();
+        let initialize_id = Mutex::new(None);
+
+        let reading_client = async {
+            let mut initialize_sender = Some(initialize_sender);
+            let mut reader = MessageReader::new(client_reader);
+            while let Some(message) = reader.read().await? {
+                trace.record(trace::Direction::FromClient, &message);
+                if message.method() == Some("initialize")
+                    && let Some(sender) = initialize_sender.take()
+                {
+                    *initialize_id.lock().unwrap() = message.id().cloned();
+                    _ = sender.send(message.clone());
+                }
+                if session.lock().unwrap().received_from_client(&message) {
+                    dispatch(message, &middlewares, &ctx, Direction::ClientToServer);
+                }
+            }
+            drop(initialize_sender);
+
+            session.lock().unwrap().end();
+            _ = client_closed_sender.send(true);
+            // The server decides when the session is over
+            std::future::pending().await
+        };
+
+        let serving = async {
+            let mut initialize = Some(initialize);
+            let mut restarts = 0;
+            loop {
+                let restarting = initialize.is_none();
+                let (initialize, replay) = match initialize.take() {
+                    Some(initialize) => (initialize, Replay::default()),
+                    None => {
+                        let (sender, receiver) = oneshot::channel();
+                        let session = session.lock().unwrap();
+                        if let Some(initialize) = session.initialize() {
+                            _ = sender.send(initialize.clone());
+                        }
+                        let (id, initialized) = ctx.server.expect_response();
+                        let replay = Replay {
+                            messages: session.replay(id.into()),
+                            initialized: Some(initialized),
+                        };
+                        (receiver, replay)
+                    }
+                };
+
+                let started = Instant::now();
+                let (reader, writer) = match start_server(ctx.clone(), initialize).await {
+                    Ok(server) => server,
+                    Err(e) => {
+                        error!("Unable to start the server: {e:#}");
+                        let message = format!("Unable to start the C# language server: {e:#}");
+                        // Shown even when initialize fails, as clients tend to only log that
+                        ctx.client
+                            .notify("window/showMessage", json!({"type": 1, "message": message}));
+                        if let Some(id) = initialize_id.lock().unwrap().clone()
+                            && !restarting
+                        {
+                            ctx.client.send(Message::from_value(json!({
+                                "jsonrpc": "2.0",
+                                "id": id,
+                                "error": {
+                                    "code": REQUEST_FAILED,
+                                    "message": message,
+                                    "data": {"retry": false},
+                                },
+                            })));
+                        }
+                        return Err(e);
+                    }
+                };
+                let result = serve(
+                    reader,
+                    writer,
+                    replay,
+                    &mut server_receiver,
+                    client_closed.clone(),
+                    exit_timeout,
+                    &middlewares,
+                    &ctx,
+                    &session,
+                    &trace,
+                )
+                .await;
+
+                if max_restarts == 0 || session.lock().unwrap().is_ending() {
+                    return result;
+                }
+
+                for error in session.lock().unwrap().server_stopped() {
+                    ctx.client.send(error);
+                }
+                ctx.server.fail_pending();
+                for middleware in middlewares.lock().unwrap().iter_mut() {
+                    middleware.on_server_restart(&ctx);
+                }
+
+                if started.elapsed() > STABLE_AFTER {
+                    restarts = 0;
+                }
+                let reason = match &result {
+                    Ok(()) => "exited".to_string(),
+                    Err(e) => format!("failed: {e:#}"),
+                };
+                if restarts == max_restarts {
+                    error!(restarts, "The server {reason}, and is not restarted again");
+                    ctx.client.notify(
+                        "window/showMessage",
+                        json!({
+                            "type": 1,
+                            "message": format!("The language server {reason}. It has been restarted {restarts} times in a row, and is not restarted again"),
+                        }),
+                    );
+                    return result.and(Err(anyhow!(
+                        "The server exited {} times in a row",
+                        restarts + 1
+                    )));
+                }
+
+                restarts += 1;
+                warn!(restarts, "The server {reason}, restarting it");
+                ctx.client.notify(
+                    "window/showMessage",
+                    json!({
+                        "type": 2,
+                        "message": format!("The language server {reason}, and has been restarted"),
+                    }),
+                );
+            }
+        };
+
+        let (done_sender, mut done) = oneshot::channel::<()>();
+        let writing_client = async {
+            let mut writer = MessageWriter::new(client_writer);
+            loop {
+                tokio::select! {
+                    biased;
+                    Some(message) = client_receiver.recv() => {
+                        session.lock().unwrap().sent_to_client(&message);
+                        trace.record(trace::Direction::ToClient, &message);
+                        writer.write(&message).await?;
+                    }
+                    _ = &mut done => break,
+                }
+            }
+            while let Ok(message) = client_receiver.try_recv() {
+                trace.record(trace::Direction::ToClient, &message);
+                writer.write(&message).await?;
+            }
+            anyhow::Ok(())
+        };
+
+        let mut writing_client = pin!(writing_client);
+        let result = tokio::select! {
+            result = reading_client => result,
+            result = serving => result,
+            result = &mut writing_client => return result,
+        };
+        _ = done_sender.send(());
+        writing_client.await?;
+        result
+    }
+}
+
+/// The messages restoring the session on a restarted server.
+#[derive(Default)]
+struct Replay {
+    /// `initialize` first, then the rest of the session
+    messages: Vec<Message>,
+    /// The response of the new server to `initialize`, which the rest waits for
+    initialized: Option<oneshot::Receiver<Message>>,
+}
+
+/// Exchanges messages with one server until its output ends, starting with the `replay` messages.
+///
+/// Once the client has closed its input, everything already queued is written before the server
+/// input is closed. From then on, or from when `exit` is written, the server has `exit_timeout` to
+/// close its output.
+#[allow(clippy::too_many_arguments)]
+async fn serve(
+    reader: impl AsyncBufRead + Unpin,
+    writer: impl AsyncWrite + Unpin,
+    replay: Replay,
+    queue: &mut mpsc::UnboundedReceiver<Message>,
+    mut client_closed: watch::Receiver<bool>,
+    exit_timeout: Duration,
+    middlewares: &Mutex<Vec<Box<dyn Middleware>>>,
+    ctx: &Context,
+    session: &Mutex<Session>,
+    trace: &Trace,
+) -> Result<()> {
+    let mut reading = pin!(async {
+        let mut reader = MessageReader::new(reader);
+        while let Some(message) = reader.read().await? {
+            trace.record(trace::Direction::FromServer, &message);
+            session.lock().unwrap().received_from_server(&message);
+            dispatch(message, middlewares, ctx, Direction::ServerToClient);
+        }
+        anyhow::Ok(())
+    });
+
+    let did_not_exit = || anyhow!("The server did not exit within {exit_timeout:?}");
+    let mut writer = MessageWriter::new(writer);
+    let mut messages = VecDeque::from(replay.messages);
+    // Like the client, the rest of the session waits for the server to answer `initialize`
+    let mut initializing = replay.initialized;
+    if initializing.is_some()
+        && let Some(initialize) = messages.pop_front()
+    {
+        trace.record(trace::Direction::ToServer, &initialize);
+        writer.write(&initialize).await?;
+    }
+    let mut exit_deadline = None;
+    loop {
+        tokio::select! {
+            biased;
+            result = &mut reading => return result,
+            _ = async {
+                match exit_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => return Err(did_not_exit()),
+            response = async { initializing.as_mut()?.await.ok() }, if initializing.is_some() => {
+                initializing = None;
+                if let Some(error) = response.as_ref().and_then(|r| r.json()?.get("error")) {
+                    warn!("The restarted server failed to initialize: {error}");
+                }
+            }
+            Some(message) = async { messages.pop_front() }, if initializing.is_none() => {
+                trace.record(trace::Direction::ToServer, &message);
+                writer.write(&message).await?;
+            }
+            Some(message) = queue.recv(), if initializing.is_none() => {
+                session.lock().unwrap().sent_to_server(&message);
+                trace.record(trace::Direction::ToServer, &message);
+                writer.write(&message).await?;
+                if message.method() == Some("exit") && exit_deadline.is_none() {
+                    exit_deadline = Some(tokio::time::Instant::now() + exit_timeout);
+                }
+            }
+            _ = async { _ = client_closed.wait_for(|closed| *closed).await } => break,
+        }
+    }
+
+    while let Ok(message) = queue.try_recv() {
+        session.lock().unwrap().sent_to_server(&message);
+        trace.record(trace::Direction::ToServer, &message);
+        writer.write(&message).await?;
+    }
+    drop(writer);
+    tokio::time::timeout(exit_timeout, reading)
+        .await
+        .map_err(|_| did_not_exit())?
+}
+
+fn dispatch(
+    message: Message,
+    middlewares: &Mutex<Vec<Box<dyn Middleware>>>,
+    ctx: &Context,
+    direction: Direction,
+) {
+    let (source, destination) = match direction {
+        Direction::ClientToServer => (&ctx.client, &ctx.server),
+        Direction::ServerToClient => (&ctx.server, &ctx.client),
+    };
+
+    let Some(mut message) = source.complete(message) else {
+        return;
+    };
+
+    for middleware in middlewares.lock().unwrap().iter_mut() {
+        let action = match direction {
+            Direction::ClientToServer => middleware.on_client_message(message, ctx),
+            Direction::ServerToClient => middleware.on_server_message(message, ctx),
+        };
+        match action {
+            Action::Forward(next) => message = next,
+            Action::Drop => return,
+            Action::Reply(reply) => {
+                source.send(reply);
+                return;
+            }
+        }
+    }
+
+    destination.send(message);
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+    use tokio::io::{BufReader, DuplexStream, duplex};
+
+    pub(crate) struct Endpoint {
+        pub reader: MessageReader<BufReader<tokio::io::ReadHalf<DuplexStream>>>,
+        pub writer: MessageWriter<tokio::io::WriteHalf<DuplexStream>>,
+    }
+
+    impl Endpoint {
+        pub async fn send(&mut self, value: Value) {
+            self.writer
+                .write(&Message::from_value(value))
+                .await
+                .unwrap();
+        }
+
+        pub async fn receive(&mut self) -> Value {
+            let message = self.reader.read().await.unwrap().unwrap();
+            message.json().unwrap().clone()
+        }
+    }
+
+    fn endpoint(stream: DuplexStream) -> Endpoint {
+        let (reader, writer) = tokio::io::split(stream);
+        Endpoint {
+            reader: MessageReader::new(BufReader::new(reader)),
+            writer: MessageWriter::new(writer),
+        }
+    }
+
+    /// Starts a proxy and returns the fake client and server ends of it.
+    pub(crate) fn start(proxy: Proxy) -> (Endpoint, Endpoint, tokio::task::JoinHandle<Result<()>>) {
+        let (client, client_proxy) = duplex(4096);
+        let (server, server_proxy) = duplex(4096);
+        let (client_proxy_reader, client_proxy_writer) = tokio::io::split(client_proxy);
+        let (server_proxy_reader, server_proxy_writer) = tokio::io::split(server_proxy);
+
+        let handle = tokio::spawn(proxy.run(
+            BufReader::new(client_proxy_reader),
+            client_proxy_writer,
+            BufReader::new(server_proxy_reader),
+            server_proxy_writer,
+        ));
+
+        (endpoint(client), endpoint(server), handle)
+    }
+
+    /// Starts a proxy whose fake server is connected once `starting` has completed for the
+    /// `initialize` request.
+    pub(crate) fn start_with_server<F>(
+        proxy: Proxy,
+        starting: impl FnOnce(Context, Message) -> F + Send + 'static,
+    ) -> (Endpoint, Endpoint, tokio::task::JoinHandle<Result<()>>)
+    where
+        F: Future<Output = Result<()>> + Send,
+    {
+        let (client, client_proxy) = duplex(4096);
+        let (server, server_proxy) = duplex(4096);
+        let (client_proxy_reader, client_proxy_writer) = tokio::io::split(client_proxy);
+        let (server_proxy_reader, server_proxy_writer) = tokio::io::split(server_proxy);
+
+        let mut connection = Some((starting, server_proxy_reader, server_proxy_writer));
+        let handle = tokio::spawn(proxy.run_with_server(
+            BufReader::new(client_proxy_reader),
+            client_proxy_writer,
+            move |ctx, initialize| {
+                let connection = connection.take();
+                async move {
+                    let (starting, reader, writer) = connection.context("Started twice")?;
+                    starting(ctx, initialize.await?).await?;
+                    Ok((BufReader::new(reader), writer))
+                }
+            },
+        ));
+
+        (endpoint(client), endpoint(server), handle)
+    }
+
+    /// Starts a proxy and returns the fake client end of it, and the fake server ends of every
+    /// server it starts.
+    pub(crate) fn start_with_servers(
+        proxy: Proxy,
+    ) -> (
+        Endpoint,
+        mpsc::UnboundedReceiver<Endpoint>,
+        tokio::task::JoinHandle<Result<()>>,
+    ) {
+        let (client, client_proxy) = duplex(4096);
+        let (client_proxy_reader, client_proxy_writer) = tokio::io::split(client_proxy);
+        let (servers_sender, servers) = mpsc::unbounded_channel();
+
+        let handle = tokio::spawn(proxy.run_with_server(
+            BufReader::new(client_proxy_reader),
+            client_proxy_writer,
+            move |_, initialize| {
+                let (server, server_proxy) = duplex(4096);
+                let (server_proxy_reader, server_proxy_writer) = tokio::io::split(server_proxy);
+                _ = servers_sender.send(endpoint(server));
+                async move {
+                    initialize.await?;
+                    Ok((BufReader::new(server_proxy_reader), server_proxy_writer))
+                }
+            },
+        ));
+
+        (endpoint(client), servers, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::{start, start_with_server, start_with_servers};
+    use super::*;
+    use serde_json::json;
+
+    struct AnswerPing;
+
+    impl Middleware for AnswerPing {
+        fn on_client_message(&mut self, message: Message, ctx: &Context) -> Action {
+            match message.method() {
+                Some("ping") => Action::Reply(Message::response(
+                    message.id().unwrap().clone(),
+                    json!("pong"),
+                )),
+                Some("secret") => Action::Drop,
+                Some("ask") => {
+                    let server = ctx.server.clone();
+                    let client = ctx.client.clone();
+                    tokio::spawn(async move {
+                        let answer = server.request("question", json!({})).await.unwrap();
+                        client.notify("answer", answer);
+                    });
+                    Action::Drop
+                }
+                _ => Action::Forward(message),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_both_directions() {
+        let (mut client, mut server, _) = start(Proxy::new());
+
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize"}))
+            .await;
+        assert_eq!(server.receive().await["method"], "initialize");
+
+        server
+            .send(json!({"jsonrpc": "2.0", "id": 1, "result": {}}))
+            .await;
+        assert_eq!(client.receive().await["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn middleware_can_reply_and_drop() {
+        let (mut client, mut server, _) = start(Proxy::new().with(AnswerPing));
+
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "secret"}))
+            .await;
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 7, "method": "ping"}))
+            .await;
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "public"}))
+            .await;
+
+        assert_eq!(client.receive().await["result"], "pong");
+        assert_eq!(server.receive().await["method"], "public");
+    }
+
+    #[tokio::test]
+    async fn traces_messages() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("trace.jsonl");
+        let proxy = Proxy::new()
+            .with(AnswerPing)
+            .trace(Trace::create(&path).unwrap());
+        let (mut client, mut server, _) = start(proxy);
+
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize"}))
+            .await;
+        server.receive().await;
+        server
+            .send(json!({"jsonrpc": "2.0", "id": 1, "result": {}}))
+            .await;
+        client.receive().await;
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 2, "method": "ping"}))
+            .await;
+        client.receive().await;
+
+        let directions: Vec<_> = trace::read(&path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.direction, entry.message["id"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(
+            directions,
+            vec![
+                (trace::Direction::FromClient, 1),
+                (trace::Direction::ToServer, 1),
+                (trace::Direction::FromServer, 1),
+                (trace::Direction::ToClient, 1),
+                (trace::Direction::FromClient, 2),
+                (trace::Direction::ToClient, 2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_responses_to_proxy_requests() {
+        let (mut client, mut server, _) = start(Proxy::new().with(AnswerPing));
+
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "ask"}))
+            .await;
+
+        let question = server.receive().await;
+        assert_eq!(question["method"], "question");
+        server
+            .send(json!({"jsonrpc": "2.0", "id": question["id"], "result": 42}))
+            .await;
+
+        let answer = client.receive().await;
+        assert_eq!(answer["method"], "answer");
+        assert_eq!(answer["params"], 42);
+    }
+
+    #[tokio::test]
+    async fn queues_client_messages_until_server_has_started() {
+        let (started, wait_for_start) = oneshot::channel::<()>();
+        let (mut client, mut server, _) = start_with_server(Proxy::new(), |ctx, initialize| {
+            ctx.client
+                .notify("starting", initialize.id().cloned().unwrap());
+            async move { Ok(wait_for_start.await?) }
+        });
+
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize"}))
+            .await;
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "initialized"}))
+            .await;
+        assert_eq!(client.receive().await["params"], 1);
+        started.send(()).unwrap();
+
+        assert_eq!(server.receive().await["method"], "initialize");
+        assert_eq!(server.receive().await["method"], "initialized");
+    }
+
+    #[tokio::test]
+    async fn ends_with_error_when_server_fails_to_start() {
+        let (mut client, _server, handle) =
+            start_with_server(Proxy::new(), |_, _| async { Err(anyhow!("no server")) });
+
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize"}))
+            .await;
+
+        let shown = client.receive().await;
+        assert_eq!(shown["method"], "window/showMessage");
+        assert_eq!(shown["params"]["type"], 1);
+        assert_eq!(
+            shown["params"]["message"],
+            "Unable to start the C# language server: no server"
+        );
+        let reply = client.receive().await;
+        assert_eq!(reply["id"], 1);
+        assert_eq!(
+            reply["error"]["message"],
+            "Unable to start the C# language server: no server"
+        );
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn restarts_server_with_session_state() {
+        let (mut client, mut servers, _) = start_with_servers(Proxy::new().restart_server(1));
+        let mut server = servers.recv().await.unwrap();
+
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}))
+            .await;
+        assert_eq!(server.receive().await["method"], "initialize");
+        server
+            .send(json!({"jsonrpc": "2.0", "id": 1, "result": {}}))
+            .await;
+        assert_eq!(client.receive().await["id"], 1);
+        for message in [
+            json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {"textDocument": {
+                "uri": "file:///A.cs", "languageId": "csharp", "version": 1, "text": "a"
+            }}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+                "textDocument": {"uri": "file:///A.cs", "version": 2},
+                "contentChanges": [{"text": "b"}]
+            }}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover", "params": {}}),
+        ] {
+            client.send(message).await;
+            server.receive().await;
+        }
+
+        drop(server);
+
+        let error = client.receive().await;
+        assert_eq!(error["id"], 2);
+        assert!(error["error"].is_object());
+        assert_eq!(client.receive().await["params"]["type"], 2);
+
+        let mut server = servers.recv().await.unwrap();
+        let initialize = server.receive().await;
+        assert_eq!(initialize["method"], "initialize");
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/hover", "params": {}}))
+            .await;
+        // Nothing else is sent before the new server has answered initialize
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), server.receive())
+                .await
+                .is_err()
+        );
+        server
+            .send(json!({"jsonrpc": "2.0", "id": initialize["id"], "result": {}}))
+            .await;
+
+        assert_eq!(server.receive().await["method"], "initialized");
+        let open = server.receive().await;
+        assert_eq!(open["method"], "textDocument/didOpen");
+        assert_eq!(open["params"]["textDocument"]["text"], "b");
+        assert_eq!(server.receive().await["id"], 3);
+        server
+            .send(json!({"jsonrpc": "2.0", "id": 3, "result": null}))
+            .await;
+        assert_eq!(client.receive().await["id"], 3);
+    }
+
+    #[tokio::test]
+    async fn stops_restarting_server_exiting_in_a_row() {
+        let (mut client, mut servers, handle) = start_with_servers(Proxy::new().restart_server(1));
+
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}))
+            .await;
+        drop(servers.recv().await.unwrap());
+        assert_eq!(client.receive().await["error"]["code"], -32803);
+        assert_eq!(client.receive().await["params"]["type"], 2);
+        drop(servers.recv().await.unwrap());
+        assert_eq!(client.receive().await["params"]["type"], 1);
+
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn does_not_restart_server_after_exit() {
+        let (mut client, mut servers, handle) = start_with_servers(Proxy::new().restart_server(1));
+        let mut server = servers.recv().await.unwrap();
+
+        client
+            .send(json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}))
+            .await;
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "exit"}))
+            .await;
+        server.receive().await;
+        assert_eq!(server.receive().await["method"], "exit");
+        drop(server);
+
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn ends_when_server_does_not_exit_in_time() {
+        let (mut client, mut server, handle) =
+            start(Proxy::new().exit_timeout(Duration::from_millis(10)));
+
+        client
+            .send(json!({"jsonrpc": "2.0", "method": "exit"}))
+            .await;
+        assert_eq!(server.receive().await["method"], "exit");
+
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn ends_when_server_closes() {
+        let (client, server, handle) = start(Proxy::new());
+
+        drop(server);
+
+        handle.await.unwrap().unwrap();
+        drop(client);
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Write as _,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde_json::Value;
+use similar::TextDiff;
+use tokio::{
+    io::{AsyncBufRead, AsyncWrite, BufReader},
+    sync::mpsc,
+};
+use tracing::warn;
+
+use crate::{
+    error::Error,
+    process::ServerProcess,
+    proxy::DEFAULT_EXIT_TIMEOUT,
+    server::{ServerOptions, start_server_without_client},
+    trace::{self, Direction, Entry},
+    transport::{Message, MessageReader, MessageWriter},
+};
+
+/// How long to wait for a response or a request of the server before giving up on it.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A response of the replayed server that does not match the recorded one.
+#[derive(Debug)]
+pub struct Difference {
+    pub method: String,
+    pub id: Value,
+    pub recorded: Value,
+    /// `None` when the server did not answer in time
+    pub replayed: Option<Value>,
+}
+
+/// Replays a trace file with a fresh server started with the options.
+pub async fn replay_file(path: &Path, options: &ServerOptions) -> Result<Vec<Difference>> {
+    let entries = trace::read(path)?;
+
+    let mut server = start_server_without_client(options).await?;
+    let stdin = server.stdin.take().ok_or(Error::NotPiped)?;
+    let stdout = server.stdout.take().ok_or(Error::NotPiped)?;
+    let process = ServerProcess::default();
+    process.replace(server);
+
+    let differences = replay(&entries, BufReader::new(stdout), stdin, REPLAY_TIMEOUT).await;
+    process.stop(DEFAULT_EXIT_TIMEOUT).await;
+    differences
+}
+
+/// Sends the messages written to the server in a trace to a fresh server, and compares its
+/// responses with the recorded ones.
+///
+/// A message is only sent once the responses received before it in the trace have arrived, so
+/// the server sees the same order of events. Responses to requests of the server are sent with
+/// the id of the matching request of the new server.
+pub async fn replay(
+    entries: &[Entry],
+    reader: impl AsyncBufRead + Unpin + Send + 'static,
+    writer: impl AsyncWrite + Unpin,
+    timeout: Duration,
+) -> Result<Vec<Difference>> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let reading = tokio::spawn(async move {
+        let mut reader = MessageReader::new(reader);
+        while let Ok(Some(message)) = reader.read().await {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut server = Server {
+        receiver,
+        responses: HashMap::new(),
+        requests: HashMap::new(),
+        timeout,
+    };
+    let mut writer = MessageWriter::new(writer);
+
+    let mut sent_requests: Vec<(String, Value)> = vec![];
+    let mut sent_ids = HashSet::new();
+    let mut recorded_responses = HashMap::new();
+    let mut server_request_methods = HashMap::new();
+    let mut awaited = vec![];
+
+    for entry in entries {
+        let message = Message::from_value(entry.message.clone());
+        let id = message.id().map(Value::to_string);
+        match entry.direction {
+            Direction::FromServer => match (id, message.method()) {
+                (Some(id), Some(method)) => {
+                    server_request_methods.insert(id, method.to_string());
+                }
+                (Some(id), None) if sent_ids.contains(&id) => {
+                    awaited.push(id.clone());
+                    recorded_responses.insert(id, entry.message.clone());
+                }
+                _ => {}
+            },
+            Direction::ToServer => {
+                for id in awaited.drain(..) {
+                    server.response(&id).await;
+                }
+
+                let mut json = entry.message.clone();
+                if message.is_response()
+                    && let Some(method) = id.as_ref().and_then(|id| server_request_methods.get(id))
+                {
+                    match server.request(method).await {
+                        Some(server_id) => json["id"] = server_id,
+                        None => continue,
+                    }
+                } else if let (Some(id), Some(method)) = (id, message.method())
+                    && message.is_request()
+                {
+                    sent_ids.insert(id);
+                    sent_requests.push((method.to_string(), json["id"].clone()));
+                }
+                writer.write(&Message::from_value(json)).await?;
+            }
+            Direction::FromClient | Direction::ToClient => {}
+        }
+    }
+
+    let mut differences = vec![];
+    for (method, id) in sent_requests {
+        let Some(recorded) = recorded_responses.remove(&id.to_string()) else {
+            continue;
+        };
+        let replayed = server.response(&id.to_string()).await;
+        if replayed.as_ref() != Some(&recorded) {
+            differences.push(Difference {
+                method,
+                id,
+                recorded,
+                replayed,
+            });
+        }
+    }
+
+    drop(writer);
+    reading.abort();
+    Ok(differences)
+}
+
+/// The messages received from the replayed server.
+struct Server {
+    receiver: mpsc::UnboundedReceiver<Message>,
+    responses: HashMap<String, Value>,
+    /// Ids of unanswered requests of the server, by method
+    requests: HashMap<String, VecDeque<Value>>,
+    timeout: Duration,
+}
+
+impl Server {
+    /// Waits for the response with the id.
+    async fn response(&mut self, id: &str) -> Option<Value> {
+        let found = self
+            .receive_until(|server| server.responses.contains_key(id))
+            .await;
+        if !found {
+            warn!(id, "The server did not answer the request in time");
+        }
+        self.responses.get(id).cloned()
+    }
+
+    /// Waits for a request of the server with the method, and returns its id.
+    async fn request(&mut self, method: &str) -> Option<Value> {
+        let found = self
+            .receive_until(|server| {
+                server
+                    .requests
+                    .get(method)
+                    .is_some_and(|ids| !ids.is_empty())
+            })
+            .await;
+        if !found {
+            warn!(method, "The server did not send the request in time");
+            return None;
+        }
+        self.requests.get_mut(method)?.pop_front()
+    }
+
+    async fn receive_until(&mut self, condition: impl Fn(&Server) -> bool) -> bool {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        while !condition(self) {
+            let Ok(Some(message)) = tokio::time::timeout_at(deadline, self.receiver.recv()).await
+            else {
+                return false;
+            };
+            let (Some(id), Some(json)) = (message.id(), message.json()) else {
+                continue;
+            };
+            match message.method() {
+                Some(method) => self
+                    .requests
+                    .entry(method.to_string())
+                    .or_default()
+                    .push_back(id.clone()),
+                None => _ = self.responses.insert(id.to_string(), json.clone()),
+            }
+        }
+        true
+    }
+}
+
+/// Describes the differences as unified diffs of the recorded and replayed responses.
+pub fn report(differences: &[Difference]) -> String {
+    let mut report = String::new();
+    for difference in differences {
+        let recorded = pretty(&difference.recorded);
+        let replayed = difference
+            .replayed
+            .as_ref()
+            .map_or("No response\n".to_string(), pretty);
+
+        _ = writeln!(report, "{} ({})", difference.method, difference.id);
+        _ = write!(
+            report,
+            "{}",
+            TextDiff::from_lines(&recorded, &replayed)
+                .unified_diff()
+                .header("recorded", "replayed")
+        );
+        report.push('\n');
+    }
+    report
+}
+
+fn pretty(value: &Value) -> String {
+    let mut pretty = serde_json::to_string_pretty(value).unwrap_or_default();
+    pretty.push('\n');
+    pretty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{BufReader, duplex};
+
+    fn entry(direction: Direction, message: Value) -> Entry {
+        Entry {
+            timestamp: 0,
+            direction,
+            message,
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_client_side_and_diffs_responses() {
+        let entries = vec![
+            entry(
+                Direction::ToServer,
+                json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+            ),
+            entry(
+                Direction::FromServer,
+                json!({"jsonrpc": "2.0", "id": "config", "method": "workspace/configuration"}),
+            ),
+            entry(
+                Direction::ToServer,
+                json!({"jsonrpc": "2.0", "id": "config", "result": [true]}),
+            ),
+            entry(
+                Direction::FromServer,
+                json!({"jsonrpc": "2.0", "id": 1, "result": {"capabilities": {}}}),
+            ),
+            entry(
+                Direction::ToServer,
+                json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover", "params": {}}),
+            ),
+            entry(
+                Direction::FromServer,
+                json!({"jsonrpc": "2.0", "id": 2, "result": {"contents": "class A"}}),
+            ),
+        ];
+
+        let (replayer, server) = duplex(4096);
+        let (replayer_reader, replayer_writer) = tokio::io::split(replayer);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        tokio::spawn(async move {
+            let mut reader = MessageReader::new(BufReader::new(server_reader));
+            let mut writer = MessageWriter::new(server_writer);
+
+            let initialize = reader.read().await.unwrap().unwrap();
+            assert_eq!(initialize.method(), Some("initialize"));
+            writer
+                .write(&Message::request(
+                    json!(7),
+                    "workspace/configuration",
+                    json!({}),
+                ))
+                .await
+                .unwrap();
+            let answer = reader.read().await.unwrap().unwrap();
+            assert_eq!(answer.id(), Some(&json!(7)));
+            writer
+                .write(&Message::response(json!(1), json!({"capabilities": {}})))
+                .await
+                .unwrap();
+
+            let hover = reader.read().await.unwrap().unwrap();
+            writer
+                .write(&Message::response(
+                    hover.id().unwrap().clone(),
+                    json!({"contents": "class B"}),
+                ))
+                .await
+                .unwrap();
+        });
+
+        let differences = replay(
+            &entries,
+            BufReader::new(replayer_reader),
+            replayer_writer,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].method, "textDocument/hover");
+        let report = report(&differences);
+        assert!(report.contains("-    \"contents\": \"class A\""));
+        assert!(report.contains("+    \"contents\": \"class B\""));
+    }
+}
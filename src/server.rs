@@ -1,26 +1,370 @@
 use anyhow::Result;
+use clap::ValueEnum;
 use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
 use std::process::Stdio;
 use std::{
-    env::temp_dir,
-    fs,
-    io::Write,
+    collections::BTreeMap,
+    env,
+    fs::{self, File, OpenOptions},
+    io,
+    ops::Range,
     path::{Path, PathBuf},
+    sync::Arc,
 };
-use tokio::process::Command;
+use tokio::process::{Child, Command};
+use tracing::{debug, info, warn};
 
+use crate::{
+    error::Error,
+    manifest::{Manifest, sha256},
+    nuget::{
+        DEFAULT_FEED, compare_versions, download_package, extract_directory, package_identity,
+        package_versions,
+    },
+    progress::{WorkDoneProgress, client_supports_work_done_progress},
+    proxy::Peer,
+    server_version::SERVER_VERSION,
+    transport::Message,
+};
+
+/// Where to get `Microsoft.CodeAnalysis.LanguageServer` from, and how to run it.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub version: String,
+    /// Whether the version was chosen explicitly. Pinned versions are kept when removing old
+    /// versions
+    pub pinned: bool,
+    pub remove_old_server_versions: bool,
+    /// Overrides the directory the server is installed to
+    pub directory: Option<PathBuf>,
+    /// NuGet v3 service indexes to download the server from, tried in order
+    pub feeds: Vec<String>,
+    pub log_level: ServerLogLevel,
+    /// Arguments passed to the server after the ones the wrapper sets
+    pub extra_args: Vec<String>,
+    /// Environment variables set for the server, e.g. `DOTNET_ROOT`
+    pub env: BTreeMap<String, String>,
+    /// Version of the Razor extension loaded into the server, if Razor is enabled
+    pub razor_version: Option<String>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            version: SERVER_VERSION.to_string(),
+            pinned: false,
+            remove_old_server_versions: true,
+            directory: None,
+            feeds: vec![DEFAULT_FEED.to_string()],
+            log_level: ServerLogLevel::default(),
+            extra_args: vec![],
+            env: BTreeMap::new(),
+            razor_version: None,
+        }
+    }
+}
+
+/// Level of the logs the server writes to the log directory.
+#[derive(ValueEnum, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerLogLevel {
+    Trace,
+    Debug,
+    #[default]
+    Information,
+    Warning,
+    Error,
+    Critical,
+    None,
+}
+
+impl ServerLogLevel {
+    /// The `--logLevel` value of the server.
+    fn argument(self) -> &'static str {
+        match self {
+            ServerLogLevel::Trace => "Trace",
+            ServerLogLevel::Debug => "Debug",
+            ServerLogLevel::Information => "Information",
+            ServerLogLevel::Warning => "Warning",
+            ServerLogLevel::Error => "Error",
+            ServerLogLevel::Critical => "Critical",
+            ServerLogLevel::None => "None",
+        }
+    }
+}
+
+/// Arguments the wrapper passes to the server itself, with the option to use instead.
+const RESERVED_ARGS: [(&str, &str); 4] = [
+    (
+        "--logLevel",
+        "Use server-log-level or --server-log-level instead",
+    ),
+    (
+        "--extensionLogDirectory",
+        "The server logs to the log directory of the wrapper",
+    ),
+    ("--stdio", "The server always communicates over stdio"),
+    ("--pipe", "The server always communicates over stdio"),
+];
+
+/// Arguments the wrapper passes to the server when Razor is enabled. `--extension` can be given
+/// more than once, so the Razor extension is loaded next to the ones in the extra arguments.
+const RESERVED_RAZOR_ARGS: [(&str, &str); 2] = [
+    (
+        "--razorSourceGenerator",
+        "The wrapper loads the Razor source generator with razor = true",
+    ),
+    (
+        "--razorDesignTimePath",
+        "The wrapper sets the Razor design-time targets with razor = true",
+    ),
+];
+
+/// The package of the Razor extension, with the Razor source generator and design-time targets.
+const RAZOR_PACKAGE_ID: &str = "Microsoft.VisualStudioCode.RazorExtension";
+const RAZOR_PACKAGE_DIRECTORY: &str = "content";
+const RAZOR_EXTENSION: &str = "Microsoft.VisualStudioCode.RazorExtension.dll";
+const RAZOR_SOURCE_GENERATOR: &str = "Microsoft.CodeAnalysis.Razor.Compiler.dll";
+const RAZOR_DESIGN_TIME_TARGETS: &str = "Targets/Microsoft.NET.Sdk.Razor.DesignTime.targets";
+
+/// `server-version` value selecting the newest version on the feeds.
+pub const LATEST_VERSION: &str = "latest";
+
+impl ServerOptions {
+    /// Checks that the extra arguments do not replace the ones set by the wrapper, including the
+    /// Razor ones when Razor is enabled, and that the environment variables can be set.
+    pub fn validate(&self) -> Result<(), Error> {
+        let razor_args = match self.razor_version {
+            Some(_) => &RESERVED_RAZOR_ARGS[..],
+            None => &[],
+        };
+        for arg in &self.extra_args {
+            let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+            if let Some((_, hint)) = RESERVED_ARGS
+                .iter()
+                .chain(razor_args)
+                .find(|(reserved, _)| *reserved == name)
+            {
+                return Err(Error::ReservedServerArg {
+                    arg: arg.clone(),
+                    hint,
+                });
+            }
+        }
+        for name in self.env.keys() {
+            if name.is_empty() || name.contains(['=', '\0']) {
+                return Err(Error::InvalidServerEnv(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the configured server version, which is either a version or `latest`.
+    pub async fn select_version(&mut self, requested: Option<&str>) {
+        match requested {
+            None => {}
+            Some(LATEST_VERSION) => self.version = latest_version(self).await,
+            Some(version) => {
+                self.version = version.to_string();
+                self.pinned = true;
+            }
+        }
+    }
+
+    /// Enables the Razor extension with the requested version or `latest`.
+    ///
+    /// The extension has to match the server, and nothing tells which version does. Without a
+    /// requested version, the one installed for the server version is used, or else the newest on
+    /// the feeds when the server is the newest too. A pinned server version needs a requested
+    /// Razor version. Razor is left disabled when no version matches, and the reason is returned
+    /// to be shown to the user.
+    pub async fn select_razor_version(&mut self, requested: Option<&str>) -> Option<String> {
+        let version = match requested {
+            Some(LATEST_VERSION) => newest_package_version(&self.feeds, RAZOR_PACKAGE_ID)
+                .await
+                .or_else(|| installed_razor_version(self)),
+            Some(version) => Some(version.to_string()),
+            None if self.pinned => None,
+            None => match installed_razor_version(self) {
+                Some(version) => Some(version),
+                None => self.newest_razor_version_for_server().await,
+            },
+        };
+
+        let Some(version) = version else {
+            let reason = match requested {
+                None => format!(
+                    "No version of the Razor extension is known to match server version {}. Set razor-version to the matching version to enable Razor, the server starts without it",
+                    self.version
+                ),
+                Some(_) => format!(
+                    "No version of {RAZOR_PACKAGE_ID} was found, the server starts without Razor"
+                ),
+            };
+            warn!("{reason}");
+            return Some(reason);
+        };
+        info!(version, "Selected Razor version");
+        self.razor_version = Some(version);
+        None
+    }
+
+    /// The newest Razor extension on the feeds, which only matches the newest server.
+    async fn newest_razor_version_for_server(&self) -> Option<String> {
+        let package_id = format!("Microsoft.CodeAnalysis.LanguageServer.{}", current_rid());
+        let newest_server = newest_package_version(&self.feeds, &package_id).await?;
+        if !newest_server.eq_ignore_ascii_case(&self.version) {
+            warn!(
+                version = self.version,
+                newest_server,
+                "The newest Razor extension is meant for the newest server. Set razor-version or server-version = \"latest\""
+            );
+            return None;
+        }
+        newest_package_version(&self.feeds, RAZOR_PACKAGE_ID).await
+    }
+}
+
+/// The newest version of a package on the feeds, if they can be reached.
+async fn newest_package_version(feeds: &[String], package_id: &str) -> Option<String> {
+    let feeds = feeds.to_vec();
+    let package_id = package_id.to_string();
+    let versions = tokio::task::spawn_blocking(move || package_versions(&feeds, &package_id)).await;
+
+    match versions {
+        Ok(Ok(mut versions)) => versions.pop(),
+        Ok(Err(e)) => {
+            warn!("Unable to find the latest version: {e:#}");
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// The newest version on the feeds, or the newest installed version when they cannot be reached.
+async fn latest_version(options: &ServerOptions) -> String {
+    let package_id = format!("Microsoft.CodeAnalysis.LanguageServer.{}", current_rid());
+    let latest = newest_package_version(&options.feeds, &package_id).await;
+
+    let version = latest
+        .or_else(|| newest_installed_version(&server_root_dir(options)))
+        .unwrap_or_else(|| options.version.clone());
+    info!(version, "Selected latest server version");
+    version
+}
+
+fn newest_installed_version(server_root_dir: &Path) -> Option<String> {
+    installed_versions(server_root_dir)
+        .into_iter()
+        .filter(|version| server_root_dir.join(version).join(current_rid()).exists())
+        .max_by(|a, b| compare_versions(a, b))
+}
+
+/// The versions installed in the server directory, for any platform. Other directories, like the
+/// log directory and interrupted installations, are skipped.
+pub fn installed_versions(server_root_dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(server_root_dir) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(|c: char| c.is_ascii_digit()))
+        .collect()
+}
+
+fn pinned_versions_file(server_root_dir: &Path) -> PathBuf {
+    server_root_dir.join("pinned-versions.json")
+}
+
+fn pinned_versions(server_root_dir: &Path) -> Vec<String> {
+    fs::read_to_string(pinned_versions_file(server_root_dir))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Records a pinned version, so other workspaces do not remove it as an old version.
+fn pin_version(server_root_dir: &Path, version: &str) -> Result<()> {
+    let mut pinned = pinned_versions(server_root_dir);
+    if pinned.iter().any(|p| p == version) {
+        return Ok(());
+    }
+    pinned.push(version.to_string());
+
+    fs::create_dir_all(server_root_dir)?;
+    fs::write(
+        pinned_versions_file(server_root_dir),
+        serde_json::to_string_pretty(&pinned)?,
+    )?;
+    Ok(())
+}
+
+/// Removes every version installed by the wrapper except the current and the pinned ones,
+/// including versions installed before installations had a manifest.
+fn remove_old_versions(server_root_dir: &Path, current_version: &str) -> Result<()> {
+    let pinned = pinned_versions(server_root_dir);
+    for version in installed_versions(server_root_dir) {
+        let dir = server_root_dir.join(&version);
+        let installed = Manifest::exists(&dir) || dir.join(current_rid()).exists();
+        if version != current_version && !pinned.contains(&version) && installed {
+            info!(version, "Removing old server version");
+            remove(dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Receives the progress of an installation, as a message and a percentage.
+type Progress = Arc<dyn Fn(&str, u32) + Send + Sync>;
+
+fn no_progress() -> Progress {
+    Arc::new(|_, _| {})
+}
+
+/// Installs the server if needed and starts it with piped input and output, for the client that
+/// sent `initialize`.
+///
+/// The installation is reported to the client as work done progress. The server runs in a process
+/// group of its own on unix, so it and the processes it starts can be stopped together.
 pub async fn start_server(
-    version: &str,
-    remove_old_server_versions: bool,
-    override_directory: Option<PathBuf>,
-) -> (tokio::process::ChildStdin, tokio::process::ChildStdout) {
-    let dir = override_directory.unwrap_or(cache_dir());
-    let log_dir = cache_dir().join("log");
-
-    let server = ensure_server_is_installed(version, remove_old_server_versions, &dir)
+    options: &ServerOptions,
+    client: &Peer,
+    initialize: &Message,
+) -> Result<Child, Error> {
+    let server = install_with_progress(options, client, initialize)
         .await
-        .expect("Unable to install server");
+        .map_err(Error::Install)?;
+    spawn(server, options)
+}
+
+/// Installs the server if needed and starts it like [`start_server`], without a client to report
+/// to. Used to replay traces.
+pub async fn start_server_without_client(options: &ServerOptions) -> Result<Child, Error> {
+    let server = ensure_server_is_installed(options, no_progress())
+        .await
+        .map_err(Error::Install)?;
+    spawn(server, options)
+}
+
+fn spawn(server: ServerPath, options: &ServerOptions) -> Result<Child, Error> {
+    let mut command = command(server, options);
+    info!(command = ?command.as_std(), "Starting server");
+    let child = command.spawn().map_err(|source| {
+        let program = PathBuf::from(command.as_std().get_program());
+        if source.kind() == io::ErrorKind::NotFound && program == Path::new("dotnet") {
+            Error::DotnetNotFound
+        } else {
+            Error::Spawn { program, source }
+        }
+    })?;
+    info!(pid = child.id(), "Server started");
+    Ok(child)
+}
 
+fn command(server: ServerPath, options: &ServerOptions) -> Command {
     let mut command = match server {
         ServerPath::Exe(path) => Command::new(path),
         ServerPath::Dll(path) => {
@@ -31,41 +375,92 @@ pub async fn start_server(
         }
     };
 
-    let command = command
-        .arg("--logLevel=Information")
+    command
+        .arg(format!("--logLevel={}", options.log_level.argument()))
         .arg("--extensionLogDirectory")
-        .arg(log_dir)
-        .arg("--stdio")
+        .arg(log_dir())
+        .arg("--stdio");
+    if options.razor_version.is_some() {
+        let razor_dir = razor_dir(options);
+        command
+            .arg("--razorSourceGenerator")
+            .arg(razor_dir.join(RAZOR_SOURCE_GENERATOR))
+            .arg("--razorDesignTimePath")
+            .arg(razor_dir.join(RAZOR_DESIGN_TIME_TARGETS));
+    }
+    command.args(&options.extra_args);
+    if options.razor_version.is_some() {
+        command
+            .arg("--extension")
+            .arg(razor_dir(options).join(RAZOR_EXTENSION));
+    }
+    command
+        .envs(&options.env)
         .stdout(Stdio::piped())
         .stdin(Stdio::piped())
-        .spawn()
-        .expect("Failed to execute command");
-
-    (command.stdin.unwrap(), command.stdout.unwrap())
+        .kill_on_drop(true);
+    #[cfg(unix)]
+    command.process_group(0);
+    command
 }
 
-pub async fn download_server(
-    version: &str,
-    remove_old_server_versions: bool,
-    override_directory: Option<PathBuf>,
-) -> PathBuf {
-    let dir = override_directory.unwrap_or(cache_dir());
+/// Installs the server when it is not installed yet, with the progress reported to the client.
+async fn install_with_progress(
+    options: &ServerOptions,
+    client: &Peer,
+    initialize: &Message,
+) -> Result<ServerPath> {
+    if installed_server(options).is_ok() && installed_razor(options).is_ok() {
+        return ensure_server_is_installed(options, no_progress()).await;
+    }
+
+    let supported = client_supports_work_done_progress(initialize);
+    let progress = Arc::new(WorkDoneProgress::create(client, supported).await);
+    progress.begin(
+        "Installing Microsoft.CodeAnalysis.LanguageServer",
+        Some(&options.version),
+    );
+
+    let reporter = progress.clone();
+    let server = ensure_server_is_installed(
+        options,
+        Arc::new(move |message, percentage| reporter.report(message, percentage)),
+    )
+    .await;
 
-    let server_path = ensure_server_is_installed(version, remove_old_server_versions, &dir)
+    if let Some(progress) = Arc::into_inner(progress) {
+        progress.end(Some(if server.is_ok() {
+            "Installed"
+        } else {
+            "Failed"
+        }));
+    }
+    server
+}
+
+pub async fn download_server(options: &ServerOptions) -> Result<PathBuf, Error> {
+    let server_path = ensure_server_is_installed(options, no_progress())
         .await
-        .expect("Unable to install server");
+        .map_err(Error::Install)?;
 
     match server_path {
-        ServerPath::Exe(path_buf) => path_buf,
-        ServerPath::Dll(path_buf) => path_buf,
+        ServerPath::Exe(path_buf) => Ok(path_buf),
+        ServerPath::Dll(path_buf) => Ok(path_buf),
     }
 }
 
+/// The directory the server and the wrapper write their logs to.
+pub fn log_dir() -> PathBuf {
+    cache_dir().join("log")
+}
+
+/// The server directory in the user cache directory, or in the temporary directory when there is
+/// no home directory to find it in.
 fn cache_dir() -> PathBuf {
-    let cache_dir = ProjectDirs::from("com", "github", "csharp-language-server")
-        .expect("Unable to find cache directory")
-        .cache_dir()
-        .to_path_buf();
+    let cache_dir = match ProjectDirs::from("com", "github", "csharp-language-server") {
+        Some(dirs) => dirs.cache_dir().to_path_buf(),
+        None => env::temp_dir().join("csharp-language-server"),
+    };
 
     cache_dir.join("server")
 }
@@ -75,60 +470,372 @@ enum ServerPath {
     Dll(PathBuf),
 }
 
+/// Installs the server from a local package file or unpacked package, for machines that cannot
+/// reach the feeds. Returns path to dll (macos) or executable (win and linux).
+pub async fn install_server_from(options: &ServerOptions, source: &Path) -> Result<PathBuf, Error> {
+    install_from(options, source).await.map_err(Error::Install)
+}
+
+async fn install_from(options: &ServerOptions, source: &Path) -> Result<PathBuf> {
+    let rid = current_rid();
+    let expected_id = format!("Microsoft.CodeAnalysis.LanguageServer.{rid}");
+    let (id, version) = package_identity(source)?;
+
+    anyhow::ensure!(
+        id.eq_ignore_ascii_case(&expected_id),
+        "{} contains {id}, but {expected_id} is needed on this platform",
+        source.display()
+    );
+    anyhow::ensure!(
+        version.eq_ignore_ascii_case(&options.version),
+        "{} contains version {version}, but version {} is configured. Set server-version to {version} to use it",
+        source.display(),
+        options.version
+    );
+
+    let _lock = lock_installation(&server_root_dir(options)).await?;
+    if options.pinned {
+        pin_version(&server_root_dir(options), &options.version)?;
+    }
+
+    let source = source.to_path_buf();
+    let server_path = install(options, move |temp_build_dir| {
+        if source.is_dir() {
+            copy_tree(
+                &source.join("content").join("LanguageServer"),
+                temp_build_dir,
+            )?;
+            Ok(None)
+        } else {
+            extract_directory(
+                &source,
+                "content/LanguageServer",
+                temp_build_dir,
+                &mut |_, _| {},
+            )?;
+            Ok(Some(sha256(&source)?))
+        }
+    })
+    .await?;
+
+    match server_path {
+        ServerPath::Exe(path_buf) => Ok(path_buf),
+        ServerPath::Dll(path_buf) => Ok(path_buf),
+    }
+}
+
 async fn ensure_server_is_installed(
-    version: &str,
-    remove_old_server_versions: bool,
-    server_root_dir: &Path,
+    options: &ServerOptions,
+    progress: Progress,
 ) -> Result<ServerPath> {
-    let server_version_dir = server_root_dir.join(version);
+    // An installed server needs no lock, so read-only and shared directories work
+    if let Ok(server) = installed_server(options)
+        && installed_razor(options).is_ok()
+    {
+        if options.pinned && !pinned_versions(&server_root_dir(options)).contains(&options.version)
+        {
+            let pinned = async {
+                let _lock = lock_installation(&server_root_dir(options)).await?;
+                pin_version(&server_root_dir(options), &options.version)
+            };
+            if let Err(e) = pinned.await {
+                warn!(
+                    version = options.version,
+                    "Unable to pin server version: {e:#}"
+                );
+            }
+        }
+        return Ok(server);
+    }
 
-    let rid = current_rid();
-    if std::path::Path::new(&server_version_dir.join(rid)).exists() {
-        return Ok(get_server_path(&server_version_dir, rid));
+    // Checked again with the lock held, as another process may have installed it meanwhile
+    let _lock = lock_installation(&server_root_dir(options)).await?;
+    if options.pinned {
+        pin_version(&server_root_dir(options), &options.version)?;
     }
 
-    create_all(server_root_dir, remove_old_server_versions)?;
-    create_all(&server_version_dir, true)?;
+    record_installation(options)?;
+    let server = match installed_server(options) {
+        Ok(server) => server,
+        Err(e) => {
+            if server_root_dir(options).join(&options.version).exists() {
+                warn!(version = options.version, "Reinstalling server: {e:#}")
+            }
+            install_package(options, progress.clone()).await?
+        }
+    };
+
+    if let Some(version) = &options.razor_version
+        && let Err(e) = installed_razor(options)
+    {
+        if razor_dir(options).exists() {
+            warn!(version, "Reinstalling Razor: {e:#}");
+        }
+        install_razor(options, version, progress).await?;
+    }
+    Ok(server)
+}
+
+/// Downloads the server package and installs it. The installation lock must be held.
+async fn install_package(options: &ServerOptions, progress: Progress) -> Result<ServerPath> {
+    let rid = current_rid();
+    let feeds = options.feeds.clone();
+    let package_id = format!("Microsoft.CodeAnalysis.LanguageServer.{rid}");
+    let version = options.version.clone();
+    install(options, move |temp_build_dir| {
+        let package = temp_build_dir.with_extension("nupkg");
+        info!(package_id, version, "Downloading server");
+        download_package(
+            &feeds,
+            &package_id,
+            &version,
+            &package,
+            &mut step_progress(&progress, "Downloading", 0..80),
+        )?;
+        info!(package = %package.display(), "Extracting server");
+        extract_directory(
+            &package,
+            "content/LanguageServer",
+            temp_build_dir,
+            &mut step_progress(&progress, "Extracting", 80..100),
+        )?;
+        Ok(Some(sha256(&package)?))
+    })
+    .await
+}
+
+/// The directory the Razor extension is installed in, inside the server version directory.
+fn razor_dir(options: &ServerOptions) -> PathBuf {
+    server_root_dir(options)
+        .join(&options.version)
+        .join("razor")
+}
+
+fn installed_razor_version(options: &ServerOptions) -> Option<String> {
+    Manifest::read(&razor_dir(options))
+        .ok()
+        .map(|manifest| manifest.version)
+}
+
+/// The installed Razor extension, if Razor is enabled and its files match the manifest of the
+/// installation. Succeeds when Razor is disabled.
+fn installed_razor(options: &ServerOptions) -> Result<()> {
+    let Some(version) = &options.razor_version else {
+        return Ok(());
+    };
+    let dir = razor_dir(options);
+    let manifest = Manifest::read(&dir)?;
+    anyhow::ensure!(
+        manifest.version.eq_ignore_ascii_case(version),
+        "Razor {} is installed instead of {version}",
+        manifest.version
+    );
+    manifest.verify(&dir, false)
+}
+
+/// Removes an installation to replace it, unless it was not installed by the wrapper.
+fn remove_installation(dir: &Path) -> Result<()> {
+    anyhow::ensure!(
+        !dir.exists() || Manifest::exists(dir),
+        "{} was not installed by csharp-language-server and is left alone. Remove it to install again",
+        dir.display()
+    );
+    remove(dir)
+}
 
-    let temp_build_root = temp_dir().join("csharp-language-server");
+/// Downloads the Razor extension into the server version directory. The installation lock must
+/// be held, and the server installed.
+async fn install_razor(options: &ServerOptions, version: &str, progress: Progress) -> Result<()> {
+    let server_root_dir = server_root_dir(options);
+    remove_interrupted_installations(&server_root_dir)?;
+    let temp_build_root = server_root_dir.join(format!(".install-{}-razor", std::process::id()));
     create(&temp_build_root, true)?;
 
-    create_csharp_project(&temp_build_root)?;
+    let feeds = options.feeds.clone();
+    let version = version.to_string();
+    let temp_build_dir = temp_build_root.join("out");
+    let build_dir = temp_build_dir.clone();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let package = build_dir.with_extension("nupkg");
+        info!(version, "Downloading Razor");
+        download_package(
+            &feeds,
+            RAZOR_PACKAGE_ID,
+            &version,
+            &package,
+            &mut step_progress(&progress, "Downloading Razor", 0..80),
+        )?;
+        extract_directory(
+            &package,
+            RAZOR_PACKAGE_DIRECTORY,
+            &build_dir,
+            &mut step_progress(&progress, "Extracting Razor", 80..100),
+        )?;
+        anyhow::ensure!(
+            build_dir.join(RAZOR_EXTENSION).exists(),
+            "The package does not contain {RAZOR_EXTENSION}"
+        );
+        Manifest::create(
+            &build_dir,
+            RAZOR_PACKAGE_ID,
+            &version,
+            Some(sha256(&package)?),
+        )?
+        .write(&build_dir)
+    })
+    .await??;
+
+    let razor_dir = razor_dir(options);
+    remove_installation(&razor_dir)?;
+    fs::rename(&temp_build_dir, &razor_dir)?;
+    remove(temp_build_root)?;
+    info!(dir = %razor_dir.display(), "Installed Razor");
+    Ok(())
+}
 
-    let res = Command::new("dotnet")
-        .arg("restore")
-        .arg(format!(
-            "-p:LanguageServerPackage=Microsoft.CodeAnalysis.LanguageServer.{rid}"
-        ))
-        .arg(format!("-p:LanguageServerVersion={version}"))
-        .current_dir(fs::canonicalize(temp_build_root.clone())?)
-        .output()
-        .await?;
+/// The path of the installed server, if its files match the manifest of the installation.
+pub fn installed_server_path(options: &ServerOptions) -> Result<PathBuf> {
+    match installed_server(options)? {
+        ServerPath::Exe(path) | ServerPath::Dll(path) => Ok(path),
+    }
+}
 
+/// The installed server, if its files match the manifest of the installation. Only the sizes of
+/// the files are compared, as hashing every file would slow down every start.
+fn installed_server(options: &ServerOptions) -> Result<ServerPath> {
+    let server_version_dir = server_root_dir(options).join(&options.version);
+    let rid = current_rid();
     anyhow::ensure!(
-        res.status.success(),
-        "dotnet restore failed with exit code: {:?}\nstdout: {}\nstderr: {}",
-        res.status.code(),
-        String::from_utf8_lossy(&res.stdout),
-        String::from_utf8_lossy(&res.stderr)
+        server_version_dir.join(rid).exists(),
+        "{} is not installed",
+        options.version
+    );
+
+    Manifest::read(&server_version_dir)?.verify(&server_version_dir, false)?;
+    Ok(get_server_path(&server_version_dir, rid))
+}
+
+/// Writes a manifest for a server installed without one, e.g. unpacked by hand into the server
+/// directory or installed by an older version of the wrapper, so it is verified from then on. The
+/// installation lock must be held.
+fn record_installation(options: &ServerOptions) -> Result<()> {
+    let server_version_dir = server_root_dir(options).join(&options.version);
+    let rid = current_rid();
+    if Manifest::exists(&server_version_dir) || !server_version_dir.join(rid).exists() {
+        return Ok(());
+    }
+    info!(
+        version = options.version,
+        "Recording the files of a server installed without a manifest"
     );
+    Manifest::create(
+        &server_version_dir,
+        &format!("Microsoft.CodeAnalysis.LanguageServer.{rid}"),
+        &options.version,
+        None,
+    )?
+    .write(&server_version_dir)
+}
+
+/// Maps the progress of an installation step onto its `range` of the whole installation,
+/// reporting only when the percentage changes.
+fn step_progress<'a>(
+    progress: &'a Progress,
+    message: &'a str,
+    range: Range<u32>,
+) -> impl FnMut(u64, u64) + 'a {
+    let mut last = None;
+    move |done, total| {
+        let step = done.min(total) * u64::from(range.end - range.start) / total.max(1);
+        let percentage = range.start + step as u32;
+        if last != Some(percentage) {
+            last = Some(percentage);
+            progress(message, percentage);
+        }
+    }
+}
+
+/// The directory the server versions are installed in.
+pub fn server_root_dir(options: &ServerOptions) -> PathBuf {
+    options.directory.clone().unwrap_or(cache_dir())
+}
 
-    let temp_build_dir = temp_build_root
-        .join("out")
-        .join(format!("microsoft.codeanalysis.languageserver.{rid}"))
-        .join(version)
-        .join("content")
-        .join("LanguageServer");
+/// Waits for other processes installing into the directory, and returns the lock, which is held
+/// until the file is dropped.
+async fn lock_installation(server_root_dir: &Path) -> Result<File> {
+    fs::create_dir_all(server_root_dir)?;
+    debug!(dir = %server_root_dir.display(), "Waiting for the installation lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(server_root_dir.join("install.lock"))?;
 
-    move_dir(&temp_build_dir, &server_version_dir)?;
+    tokio::task::spawn_blocking(move || {
+        file.lock()?;
+        Ok(file)
+    })
+    .await?
+}
+
+/// Installs the server version, with `fetch` putting the `content/LanguageServer` directory of
+/// the package into the directory it is given, and returning the hash of the package if any.
+///
+/// The installation lock must be held. The server is built in a directory of its own next to the
+/// version directory, and renamed into place once complete, so an interrupted installation leaves
+/// no partial version behind.
+async fn install(
+    options: &ServerOptions,
+    fetch: impl FnOnce(&Path) -> Result<Option<String>> + Send + 'static,
+) -> Result<ServerPath> {
+    let server_root_dir = server_root_dir(options);
+    let server_version_dir = server_root_dir.join(&options.version);
+    let rid = current_rid();
+
+    // Versions in a configured directory are left to whoever put them there
+    if options.remove_old_server_versions && options.directory.is_none() {
+        remove_old_versions(&server_root_dir, &options.version)?;
+    }
+    remove_interrupted_installations(&server_root_dir)?;
+
+    let temp_build_root = server_root_dir.join(format!(".install-{}", std::process::id()));
+    create(&temp_build_root, true)?;
+
+    let temp_build_dir = temp_build_root.join("out");
+    let fetch_dir = temp_build_dir.clone();
+    let package_sha256 = tokio::task::spawn_blocking(move || fetch(&fetch_dir)).await??;
+
+    anyhow::ensure!(
+        temp_build_dir.join(rid).exists(),
+        "The package does not contain a server for {rid}"
+    );
+
+    Manifest::create(
+        &temp_build_dir,
+        &format!("Microsoft.CodeAnalysis.LanguageServer.{rid}"),
+        &options.version,
+        package_sha256,
+    )?
+    .write(&temp_build_dir)?;
+
+    remove_installation(&server_version_dir)?;
+    fs::rename(&temp_build_dir, &server_version_dir)?;
     remove(temp_build_root)?;
+    info!(version = options.version, dir = %server_version_dir.display(), "Installed server");
 
     Ok(get_server_path(&server_version_dir, rid))
 }
 
-fn create_csharp_project(temp_build_root: &Path) -> Result<()> {
-    let mut csproj_file = std::fs::File::create(temp_build_root.join("ServerDownload.csproj"))?;
-    csproj_file.write_all(CSPROJ.as_bytes())?;
+/// Removes build directories left by installations that did not complete. Only safe while holding
+/// the installation lock.
+fn remove_interrupted_installations(server_root_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(server_root_dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(".install-") {
+            info!(dir = %entry.path().display(), "Removing interrupted installation");
+            remove(entry.path())?;
+        }
+    }
     Ok(())
 }
 
@@ -143,23 +850,9 @@ fn get_server_path(server_version_dir: &Path, rid: &str) -> ServerPath {
     }
 }
 
-const CSPROJ: &str = r#"
-<Project Sdk="Microsoft.NET.Sdk">
-    <PropertyGroup>
-        <RestoreSources>https://pkgs.dev.azure.com/azure-public/vside/_packaging/vs-impl/nuget/v3/index.json</RestoreSources>
-        <RestorePackagesPath>out</RestorePackagesPath>
-        <TargetFramework>netstandard2.0</TargetFramework>
-        <DisableImplicitNuGetFallbackFolder>true</DisableImplicitNuGetFallbackFolder>
-        <DisableImplicitFrameworkReferences>true</DisableImplicitFrameworkReferences>
-    </PropertyGroup>
-
-    <ItemGroup>
-        <PackageDownload Include="$(LanguageServerPackage)" Version="[$(LanguageServerVersion)]" />
-    </ItemGroup>
-</Project>"#;
-
+/// The runtime identifier of the server package for this platform.
 #[allow(unreachable_code)]
-const fn current_rid() -> &'static str {
+pub const fn current_rid() -> &'static str {
     #[cfg(all(target_os = "windows", target_arch = "x86_64"))]
     return "win-x64";
 
@@ -187,13 +880,6 @@ const fn current_rid() -> &'static str {
     "neutral"
 }
 
-fn create_all<P: AsRef<Path>>(path: P, erase: bool) -> Result<()> {
-    if erase && path.as_ref().exists() {
-        remove(&path)?;
-    }
-    Ok(fs::create_dir_all(&path)?)
-}
-
 fn remove<P: AsRef<Path>>(path: P) -> Result<()> {
     if path.as_ref().exists() {
         Ok(fs::remove_dir_all(path)?)
@@ -209,17 +895,6 @@ fn create<P: AsRef<Path>>(path: P, erase: bool) -> Result<()> {
     Ok(fs::create_dir(&path)?)
 }
 
-fn move_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
-    match fs::rename(src, dst) {
-        Ok(()) => Ok(()),
-        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
-            copy_tree(src, dst)?;
-            fs::remove_dir_all(src)
-        }
-        Err(e) => Err(e),
-    }
-}
-
 fn copy_tree(src: &Path, dst: &Path) -> std::io::Result<()> {
     fs::create_dir_all(dst)?;
     for entry in fs::read_dir(src)? {
@@ -235,3 +910,473 @@ fn copy_tree(src: &Path, dst: &Path) -> std::io::Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nuget::test_support::{package, serve};
+    use crate::proxy::{Proxy, test_support::start_with_server};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn nuspec(id: &str, version: &str) -> String {
+        format!("<package><metadata><id>{id}</id><version>{version}</version></metadata></package>")
+    }
+
+    fn options(directory: &Path) -> ServerOptions {
+        ServerOptions {
+            version: "1.0.0".to_string(),
+            directory: Some(directory.to_path_buf()),
+            feeds: vec![],
+            ..ServerOptions::default()
+        }
+    }
+
+    #[test]
+    fn passes_log_level_arguments_and_environment() {
+        let options = ServerOptions {
+            log_level: ServerLogLevel::Debug,
+            extra_args: vec!["--razorSourceGenerator=Razor.dll".to_string()],
+            env: BTreeMap::from([("DOTNET_ROOT".to_string(), "/opt/dotnet".to_string())]),
+            ..ServerOptions::default()
+        };
+        options.validate().unwrap();
+
+        let command = command(ServerPath::Exe(PathBuf::from("server")), &options);
+        let command = command.as_std();
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args[0], "--logLevel=Debug");
+        assert_eq!(args[3], "--stdio");
+        assert_eq!(args[4], "--razorSourceGenerator=Razor.dll");
+        let envs: Vec<_> = command.get_envs().collect();
+        assert_eq!(
+            envs,
+            vec![("DOTNET_ROOT".as_ref(), Some("/opt/dotnet".as_ref()))]
+        );
+    }
+
+    #[test]
+    fn rejects_arguments_set_by_wrapper() {
+        let with_args = |args: &[&str]| ServerOptions {
+            extra_args: args.iter().map(|arg| arg.to_string()).collect(),
+            ..ServerOptions::default()
+        };
+
+        assert!(matches!(
+            with_args(&["--logLevel=Trace"]).validate(),
+            Err(Error::ReservedServerArg { .. })
+        ));
+        assert!(with_args(&["--stdio"]).validate().is_err());
+        assert!(with_args(&["--telemetryLevel=off"]).validate().is_ok());
+        assert!(with_args(&["--extension=Other.dll"]).validate().is_ok());
+        let with_razor = |args| ServerOptions {
+            razor_version: Some("10.0.0".to_string()),
+            ..with_args(args)
+        };
+        assert!(matches!(
+            with_razor(&["--razorSourceGenerator=Other.dll"]).validate(),
+            Err(Error::ReservedServerArg { .. })
+        ));
+        let options = with_razor(&["--extension", "Other.dll"]);
+        options.validate().unwrap();
+        let command = command(ServerPath::Exe(PathBuf::from("server")), &options);
+        let extensions = command
+            .as_std()
+            .get_args()
+            .filter(|arg| *arg == "--extension")
+            .count();
+        assert_eq!(extensions, 2);
+
+        let options = ServerOptions {
+            env: BTreeMap::from([("A=B".to_string(), String::new())]),
+            ..ServerOptions::default()
+        };
+        assert!(matches!(
+            options.validate(),
+            Err(Error::InvalidServerEnv(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn installs_from_package_file() {
+        let tmp = TempDir::new().unwrap();
+        let rid = current_rid();
+        let nupkg = tmp.path().join("server.nupkg");
+        let nuspec = nuspec(
+            &format!("Microsoft.CodeAnalysis.LanguageServer.{rid}"),
+            "1.0.0",
+        );
+        let server_file = format!("content/LanguageServer/{rid}/Server.txt");
+        fs::write(
+            &nupkg,
+            package(&[("server.nuspec", &nuspec), (&server_file, "server")]),
+        )
+        .unwrap();
+        let options = options(&tmp.path().join("servers"));
+
+        install_server_from(&options, &nupkg).await.unwrap();
+
+        let installed = tmp.path().join("servers").join("1.0.0").join(rid);
+        assert_eq!(
+            fs::read_to_string(installed.join("Server.txt")).unwrap(),
+            "server"
+        );
+    }
+
+    #[tokio::test]
+    async fn installs_from_unpacked_package() {
+        let tmp = TempDir::new().unwrap();
+        let rid = current_rid();
+        let unpacked = tmp.path().join("unpacked");
+        let server_dir = unpacked.join("content").join("LanguageServer").join(rid);
+        fs::create_dir_all(&server_dir).unwrap();
+        fs::write(server_dir.join("Server.txt"), "server").unwrap();
+        fs::write(
+            unpacked.join("server.nuspec"),
+            nuspec(
+                &format!("Microsoft.CodeAnalysis.LanguageServer.{rid}"),
+                "1.0.0",
+            ),
+        )
+        .unwrap();
+        let options = options(&tmp.path().join("servers"));
+
+        install_server_from(&options, &unpacked).await.unwrap();
+
+        let installed = tmp.path().join("servers").join("1.0.0").join(rid);
+        assert!(installed.join("Server.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn reinstalls_damaged_installation() {
+        let tmp = TempDir::new().unwrap();
+        let rid = current_rid();
+        let id = format!("Microsoft.CodeAnalysis.LanguageServer.{rid}");
+        let server_file = format!("content/LanguageServer/{rid}/Server.txt");
+        let address = serve(HashMap::from([(
+            format!("/packages/{0}/1.0.0/{0}.1.0.0.nupkg", id.to_lowercase()),
+            package(&[
+                ("server.nuspec", &nuspec(&id, "1.0.0")),
+                (&server_file, "server"),
+            ]),
+        )]));
+        let options = ServerOptions {
+            feeds: vec![format!("{address}/index.json")],
+            ..options(tmp.path())
+        };
+        let installed = tmp.path().join("1.0.0");
+
+        download_server(&options).await.unwrap();
+        let manifest = Manifest::read(&installed).unwrap();
+        assert!(manifest.package_sha256.is_some());
+
+        fs::write(installed.join(rid).join("Server.txt"), "ser").unwrap();
+        download_server(&options).await.unwrap();
+
+        assert_eq!(
+            fs::read_to_string(installed.join(rid).join("Server.txt")).unwrap(),
+            "server"
+        );
+    }
+
+    #[tokio::test]
+    async fn records_installation_without_manifest() {
+        let tmp = TempDir::new().unwrap();
+        let server_dir = tmp.path().join("1.0.0").join(current_rid());
+        fs::create_dir_all(&server_dir).unwrap();
+        fs::write(server_dir.join("Server.txt"), "unpacked by hand").unwrap();
+        let other = tmp.path().join("0.9.0").join(current_rid());
+        fs::create_dir_all(&other).unwrap();
+
+        download_server(&options(tmp.path())).await.unwrap();
+
+        let manifest = Manifest::read(&tmp.path().join("1.0.0")).unwrap();
+        assert_eq!(manifest.files.len(), 1);
+        assert!(server_dir.join("Server.txt").exists());
+        installed_server(&options(tmp.path())).unwrap();
+        fs::write(server_dir.join("Server.txt"), "changed").unwrap();
+        assert!(installed_server(&options(tmp.path())).is_err());
+        assert!(other.exists());
+        assert!(remove_installation(&tmp.path().join("0.9.0")).is_err());
+        assert!(other.exists());
+    }
+
+    #[tokio::test]
+    async fn starts_installed_server_without_lock() {
+        let tmp = TempDir::new().unwrap();
+        let installed = tmp.path().join("1.0.0");
+        fs::create_dir_all(installed.join(current_rid())).unwrap();
+        Manifest::create(&installed, "Server", "1.0.0", None)
+            .unwrap()
+            .write(&installed)
+            .unwrap();
+
+        download_server(&options(tmp.path())).await.unwrap();
+
+        assert!(!tmp.path().join("install.lock").exists());
+    }
+
+    #[tokio::test]
+    async fn reports_installation_progress_to_client() {
+        let tmp = TempDir::new().unwrap();
+        let rid = current_rid();
+        let id = format!("Microsoft.CodeAnalysis.LanguageServer.{rid}");
+        let server_file = format!("content/LanguageServer/{rid}/Server.txt");
+        let address = serve(HashMap::from([(
+            format!("/packages/{0}/1.0.0/{0}.1.0.0.nupkg", id.to_lowercase()),
+            package(&[
+                ("server.nuspec", &nuspec(&id, "1.0.0")),
+                (&server_file, "server"),
+            ]),
+        )]));
+        let options = ServerOptions {
+            feeds: vec![format!("{address}/index.json")],
+            ..options(tmp.path())
+        };
+        let (mut client, _server, _) =
+            start_with_server(Proxy::new(), move |ctx, initialize| async move {
+                install_with_progress(&options, &ctx.client, &initialize).await?;
+                Ok(())
+            });
+
+        client
+            .send(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "initialize",
+                "params": {"capabilities": {"window": {"workDoneProgress": true}}}
+            }))
+            .await;
+        let create = client.receive().await;
+        assert_eq!(create["method"], "window/workDoneProgress/create");
+        client
+            .send(json!({"jsonrpc": "2.0", "id": create["id"], "result": null}))
+            .await;
+
+        let mut progress = vec![];
+        loop {
+            let value = client.receive().await["params"]["value"].clone();
+            progress.push(value.clone());
+            if value["kind"] == "end" {
+                break;
+            }
+        }
+        assert_eq!(progress[0]["kind"], "begin");
+        assert!(progress.iter().any(|p| p["message"] == "Downloading"));
+        assert_eq!(progress.last().unwrap()["message"], "Installed");
+    }
+
+    #[tokio::test]
+    async fn installs_razor_next_to_server() {
+        let tmp = TempDir::new().unwrap();
+        let rid = current_rid();
+        let id = format!("Microsoft.CodeAnalysis.LanguageServer.{rid}");
+        let server_file = format!("content/LanguageServer/{rid}/Server.txt");
+        let razor_extension = format!("content/{RAZOR_EXTENSION}");
+        let address = serve(HashMap::from([
+            (
+                format!("/packages/{}/index.json", id.to_lowercase()),
+                br#"{"versions": ["0.9.0", "1.0.0"]}"#.to_vec(),
+            ),
+            (
+                format!("/packages/{0}/1.0.0/{0}.1.0.0.nupkg", id.to_lowercase()),
+                package(&[
+                    ("server.nuspec", &nuspec(&id, "1.0.0")),
+                    (&server_file, "server"),
+                ]),
+            ),
+            (
+                format!("/packages/{0}/index.json", RAZOR_PACKAGE_ID.to_lowercase()),
+                br#"{"versions": ["10.0.0-1.1", "10.0.0-2.1"]}"#.to_vec(),
+            ),
+            (
+                format!(
+                    "/packages/{0}/10.0.0-2.1/{0}.10.0.0-2.1.nupkg",
+                    RAZOR_PACKAGE_ID.to_lowercase()
+                ),
+                package(&[
+                    ("razor.nuspec", &nuspec(RAZOR_PACKAGE_ID, "10.0.0-2.1")),
+                    (&razor_extension, "extension"),
+                ]),
+            ),
+        ]));
+        let mut options = ServerOptions {
+            feeds: vec![format!("{address}/index.json")],
+            ..options(tmp.path())
+        };
+
+        assert_eq!(options.select_razor_version(None).await, None);
+        assert_eq!(options.razor_version.as_deref(), Some("10.0.0-2.1"));
+        download_server(&options).await.unwrap();
+
+        let razor_dir = tmp.path().join("1.0.0").join("razor");
+        assert!(razor_dir.join(RAZOR_EXTENSION).exists());
+        installed_server(&options).unwrap();
+        installed_razor(&options).unwrap();
+
+        let command = command(ServerPath::Exe(PathBuf::from("server")), &options);
+        let args: Vec<_> = command.as_std().get_args().collect();
+        let extension = razor_dir.join(RAZOR_EXTENSION);
+        assert!(
+            args.windows(2)
+                .any(|arg| arg[0] == "--extension" && arg[1] == extension.as_os_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn starts_without_razor_when_versions_cannot_be_matched() {
+        let tmp = TempDir::new().unwrap();
+        let address = serve(HashMap::from([
+            (
+                format!(
+                    "/packages/microsoft.codeanalysis.languageserver.{}/index.json",
+                    current_rid()
+                ),
+                br#"{"versions": ["1.0.0", "2.0.0"]}"#.to_vec(),
+            ),
+            (
+                format!("/packages/{}/index.json", RAZOR_PACKAGE_ID.to_lowercase()),
+                br#"{"versions": ["10.0.0-2.1"]}"#.to_vec(),
+            ),
+        ]));
+        let mut options = ServerOptions {
+            feeds: vec![format!("{address}/index.json")],
+            ..options(tmp.path())
+        };
+
+        let reason = options.select_razor_version(None).await.unwrap();
+        assert!(reason.contains("server version 1.0.0"));
+        assert_eq!(options.razor_version, None);
+
+        options.version = "2.0.0".to_string();
+        options.pinned = true;
+        assert!(options.select_razor_version(None).await.is_some());
+        assert_eq!(options.razor_version, None);
+
+        assert_eq!(options.select_razor_version(Some("10.0.0-1.1")).await, None);
+        assert_eq!(options.razor_version.as_deref(), Some("10.0.0-1.1"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_installs_wait_for_each_other() {
+        let tmp = TempDir::new().unwrap();
+        let rid = current_rid();
+        let id = format!("Microsoft.CodeAnalysis.LanguageServer.{rid}");
+        let server_file = format!("content/LanguageServer/{rid}/Server.txt");
+        let address = serve(HashMap::from([(
+            format!("/packages/{0}/1.0.0/{0}.1.0.0.nupkg", id.to_lowercase()),
+            package(&[
+                ("server.nuspec", &nuspec(&id, "1.0.0")),
+                (&server_file, "server"),
+            ]),
+        )]));
+        let options = ServerOptions {
+            feeds: vec![format!("{address}/index.json")],
+            ..options(tmp.path())
+        };
+        let interrupted = tmp.path().join(".install-0");
+        fs::create_dir_all(&interrupted).unwrap();
+
+        let (first, second) = tokio::join!(download_server(&options), download_server(&options));
+
+        assert_eq!(first.unwrap(), second.unwrap());
+        let installed = tmp.path().join("1.0.0");
+        Manifest::read(&installed)
+            .unwrap()
+            .verify(&installed, true)
+            .unwrap();
+        assert!(!interrupted.exists());
+        assert_eq!(installed_versions(tmp.path()), vec!["1.0.0"]);
+    }
+
+    #[test]
+    fn keeps_current_and_pinned_versions() {
+        let tmp = TempDir::new().unwrap();
+        for version in ["1.0.0", "2.0.0", "3.0.0"] {
+            let dir = tmp.path().join(version);
+            fs::create_dir_all(&dir).unwrap();
+            Manifest::create(&dir, "Server", version, None)
+                .unwrap()
+                .write(&dir)
+                .unwrap();
+        }
+        // Installed before installations had a manifest
+        fs::create_dir_all(tmp.path().join("4.0.0").join(current_rid())).unwrap();
+        fs::create_dir_all(tmp.path().join("5.0.0")).unwrap();
+        fs::create_dir_all(tmp.path().join("log")).unwrap();
+
+        pin_version(tmp.path(), "1.0.0").unwrap();
+        remove_old_versions(tmp.path(), "3.0.0").unwrap();
+
+        let mut remaining = installed_versions(tmp.path());
+        remaining.sort();
+        assert_eq!(remaining, vec!["1.0.0", "3.0.0", "5.0.0"]);
+        assert!(tmp.path().join("log").exists());
+    }
+
+    #[tokio::test]
+    async fn selects_latest_version() {
+        let rid = current_rid();
+        let address = serve(HashMap::from([(
+            format!("/packages/microsoft.codeanalysis.languageserver.{rid}/index.json"),
+            br#"{"versions": ["5.3.0-1.1", "5.10.0-1.1", "5.4.0-2.26080.13"]}"#.to_vec(),
+        )]));
+        let mut options = ServerOptions {
+            feeds: vec![format!("{address}/index.json")],
+            ..ServerOptions::default()
+        };
+
+        options.select_version(Some(LATEST_VERSION)).await;
+
+        assert_eq!(options.version, "5.10.0-1.1");
+        assert!(!options.pinned);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_newest_installed_version() {
+        let tmp = TempDir::new().unwrap();
+        let rid = current_rid();
+        for version in ["5.9.0", "5.10.0", "6.0.0"] {
+            fs::create_dir_all(tmp.path().join(version)).unwrap();
+        }
+        for version in ["5.9.0", "5.10.0"] {
+            fs::create_dir_all(tmp.path().join(version).join(rid)).unwrap();
+        }
+        let mut options = options(tmp.path());
+
+        options.select_version(Some(LATEST_VERSION)).await;
+
+        assert_eq!(options.version, "5.10.0");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_version_or_platform() {
+        let tmp = TempDir::new().unwrap();
+        let options = options(&tmp.path().join("servers"));
+
+        let other_version = tmp.path().join("version.nupkg");
+        let id = format!("Microsoft.CodeAnalysis.LanguageServer.{}", current_rid());
+        fs::write(
+            &other_version,
+            package(&[("server.nuspec", &nuspec(&id, "2.0.0"))]),
+        )
+        .unwrap();
+        assert!(install_server_from(&options, &other_version).await.is_err());
+
+        let other_platform = tmp.path().join("platform.nupkg");
+        let id = "Microsoft.CodeAnalysis.LanguageServer.other-rid";
+        fs::write(
+            &other_platform,
+            package(&[("server.nuspec", &nuspec(id, "1.0.0"))]),
+        )
+        .unwrap();
+        assert!(
+            install_server_from(&options, &other_platform)
+                .await
+                .is_err()
+        );
+
+        assert!(!tmp.path().join("servers").join("1.0.0").exists());
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, HashSet};
+
+use serde_json::{Value, json};
+
+use crate::{proxy::is_proxy_request_id, transport::Message};
+
+/// `RequestFailed` from the LSP specification.
+pub(crate) const REQUEST_FAILED: i64 = -32803;
+
+/// The state of the session with the server, as seen in the messages passing through the proxy.
+///
+/// It is what a restarted server has to be told to continue where the previous one stopped: the
+/// `initialize` handshake, the solutions and projects that were opened, and the open documents
+/// with their current content.
+#[derive(Default)]
+pub struct Session {
+    initialize: Option<Message>,
+    initialized: Option<Message>,
+    workspace: Vec<Message>,
+    documents: BTreeMap<String, Document>,
+    /// Client requests written to the server and not answered yet. Requests of the proxy itself
+    /// are tracked by its peers
+    client_requests: HashSet<String>,
+    /// Server requests written to the client and not answered yet
+    server_requests: HashSet<String>,
+    /// Server requests of a previous server, whose responses must not reach the current one
+    stale_server_requests: HashSet<String>,
+    ending: bool,
+}
+
+struct Document {
+    language_id: Value,
+    version: Value,
+    text: String,
+}
+
+impl Session {
+    /// Records a message written to the server.
+    pub fn sent_to_server(&mut self, message: &Message) {
+        if message.is_request()
+            && let Some(id) = message.id()
+            && !is_proxy_request_id(id)
+        {
+            self.client_requests.insert(id.to_string());
+        }
+
+        let params = message.params().cloned().unwrap_or_default();
+        match message.method() {
+            Some("initialize") => self.initialize = Some(message.clone()),
+            Some("initialized") => self.initialized = Some(message.clone()),
+            Some("solution/open" | "project/open") => self.workspace.push(message.clone()),
+            Some("shutdown" | "exit") => self.ending = true,
+            Some("textDocument/didOpen") => {
+                let document = &params["textDocument"];
+                if let Some(uri) = document["uri"].as_str() {
+                    self.documents.insert(
+                        uri.to_string(),
+                        Document {
+                            language_id: document["languageId"].clone(),
+                            version: document["version"].clone(),
+                            text: document["text"].as_str().unwrap_or_default().to_string(),
+                        },
+                    );
+                }
+            }
+            Some("textDocument/didChange") => {
+                let Some(document) = params["textDocument"]["uri"]
+                    .as_str()
+                    .and_then(|uri| self.documents.get_mut(uri))
+                else {
+                    return;
+                };
+                document.version = params["textDocument"]["version"].clone();
+                for change in params["contentChanges"].as_array().into_iter().flatten() {
+                    document.apply(change);
+                }
+            }
+            Some("textDocument/didClose") => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.documents.remove(uri);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Records a message read from the server.
+    pub fn received_from_server(&mut self, message: &Message) {
+        if message.is_response()
+            && let Some(id) = message.id()
+        {
+            self.client_requests.remove(&id.to_string());
+        }
+    }
+
+    /// Records a message written to the client.
+    pub fn sent_to_client(&mut self, message: &Message) {
+        if message.is_request()
+            && let Some(id) = message.id()
+            && !is_proxy_request_id(id)
+        {
+            self.server_requests.insert(id.to_string());
+        }
+    }
+
+    /// Records a message read from the client. Returns whether it should be dispatched, which is
+    /// not the case for answers to requests of a previous server.
+    pub fn received_from_client(&mut self, message: &Message) -> bool {
+        if message.is_response()
+            && let Some(id) = message.id()
+        {
+            let id = id.to_string();
+            self.server_requests.remove(&id);
+            return !self.stale_server_requests.remove(&id);
+        }
+        true
+    }
+
+    /// The client ended the session, by asking the server to shut down or closing its input.
+    pub fn end(&mut self) {
+        self.ending = true;
+    }
+
+    /// Whether the server is expected to exit.
+    pub fn is_ending(&self) -> bool {
+        self.ending
+    }
+
+    pub fn initialize(&self) -> Option<&Message> {
+        self.initialize.as_ref()
+    }
+
+    /// Forgets the requests the stopped server will never answer, and returns the error responses
+    /// to send to the client for its own.
+    pub fn server_stopped(&mut self) -> Vec<Message> {
+        self.stale_server_requests
+            .extend(self.server_requests.drain());
+
+        self.client_requests
+            .drain()
+            .filter_map(|id| serde_json::from_str(&id).ok())
+            .map(|id| {
+                Message::error_response(
+                    id,
+                    REQUEST_FAILED,
+                    "The server exited before answering the request",
+                )
+            })
+            .collect()
+    }
+
+    /// The messages restoring the session on a new server, with `initialize` sent as a request
+    /// with the given id.
+    pub fn replay(&self, initialize_id: Value) -> Vec<Message> {
+        let mut messages = vec![];
+
+        if let Some(mut initialize) = self.initialize.as_ref().and_then(|m| m.json()).cloned() {
+            initialize["id"] = initialize_id;
+            messages.push(Message::from_value(initialize));
+        }
+        messages.extend(self.initialized.clone());
+        messages.extend(self.workspace.iter().cloned());
+
+        for (uri, document) in &self.documents {
+            messages.push(Message::notification(
+                "textDocument/didOpen",
+                json!({
+                    "textDocument": {
+                        "uri": uri,
+                        "languageId": document.language_id,
+                        "version": document.version,
+                        "text": document.text,
+                    }
+                }),
+            ));
+        }
+        messages
+    }
+}
+
+impl Document {
+    /// Applies a `TextDocumentContentChangeEvent`, which replaces either a range or the whole text.
+    fn apply(&mut self, change: &Value) {
+        let text = change["text"].as_str().unwrap_or_default();
+        let range = &change["range"];
+        if range.is_null() {
+            self.text = text.to_string();
+            return;
+        }
+
+        let start = offset(&self.text, &range["start"]);
+        let end = offset(&self.text, &range["end"]).max(start);
+        self.text.replace_range(start..end, text);
+    }
+}
+
+/// The byte offset of an LSP position, with the character counted in UTF-16 code units. Positions
+/// past the end of a line or the text are moved back to it.
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or_default();
+    let character = position["character"].as_u64().unwrap_or_default();
+
+    let mut line_start = 0;
+    for _ in 0..line {
+        match text[line_start..].find('\n') {
+            Some(index) => line_start += index + 1,
+            None => return text.len(),
+        }
+    }
+
+    let line_text = &text[line_start..];
+    let line_text = &line_text[..line_text.find('\n').unwrap_or(line_text.len())];
+    let mut units = 0;
+    for (index, c) in line_text.char_indices() {
+        if units >= character {
+            return line_start + index;
+        }
+        units += c.len_utf16() as u64;
+    }
+    line_start + line_text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(method: &str, params: Value) -> Message {
+        Message::notification(method, params)
+    }
+
+    fn change(start: (u64, u64), end: (u64, u64), text: &str) -> Value {
+        json!({
+            "range": {
+                "start": {"line": start.0, "character": start.1},
+                "end": {"line": end.0, "character": end.1}
+            },
+            "text": text
+        })
+    }
+
+    #[test]
+    fn replays_handshake_workspace_and_documents() {
+        let mut session = Session::default();
+        session.sent_to_server(&Message::request(
+            json!(1),
+            "initialize",
+            json!({"rootUri": "file:///repo"}),
+        ));
+        session.sent_to_server(&notification("initialized", json!({})));
+        session.sent_to_server(&notification(
+            "solution/open",
+            json!({"solution": "file:///repo/App.sln"}),
+        ));
+        session.sent_to_server(&notification(
+            "textDocument/didOpen",
+            json!({"textDocument": {
+                "uri": "file:///repo/A.cs", "languageId": "csharp", "version": 1, "text": "class A {}"
+            }}),
+        ));
+        session.sent_to_server(&notification(
+            "textDocument/didOpen",
+            json!({"textDocument": {
+                "uri": "file:///repo/B.cs", "languageId": "csharp", "version": 1, "text": ""
+            }}),
+        ));
+        session.sent_to_server(&notification(
+            "textDocument/didClose",
+            json!({"textDocument": {"uri": "file:///repo/B.cs"}}),
+        ));
+
+        let replay = session.replay(json!("restart"));
+
+        let methods: Vec<_> = replay.iter().map(|m| m.method().unwrap()).collect();
+        assert_eq!(
+            methods,
+            vec![
+                "initialize",
+                "initialized",
+                "solution/open",
+                "textDocument/didOpen"
+            ]
+        );
+        assert_eq!(replay[0].id(), Some(&json!("restart")));
+        assert_eq!(
+            replay[3].params().unwrap()["textDocument"]["uri"],
+            "file:///repo/A.cs"
+        );
+    }
+
+    #[test]
+    fn applies_document_changes() {
+        let mut session = Session::default();
+        session.sent_to_server(&notification(
+            "textDocument/didOpen",
+            json!({"textDocument": {
+                "uri": "file:///A.cs", "languageId": "csharp", "version": 1,
+                "text": "class A\n{\n    // 😀 x\n}\n"
+            }}),
+        ));
+
+        session.sent_to_server(&notification(
+            "textDocument/didChange",
+            json!({
+                "textDocument": {"uri": "file:///A.cs", "version": 3},
+                "contentChanges": [
+                    change((0, 6), (0, 7), "Program"),
+                    change((2, 10), (2, 11), "y"),
+                    change((4, 0), (4, 0), "// end"),
+                ]
+            }),
+        ));
+
+        let document = &session.documents["file:///A.cs"];
+        assert_eq!(document.text, "class Program\n{\n    // 😀 y\n}\n// end");
+        assert_eq!(document.version, 3);
+
+        session.sent_to_server(&notification(
+            "textDocument/didChange",
+            json!({
+                "textDocument": {"uri": "file:///A.cs", "version": 4},
+                "contentChanges": [{"text": "class B {}"}]
+            }),
+        ));
+        assert_eq!(session.documents["file:///A.cs"].text, "class B {}");
+    }
+
+    #[test]
+    fn fails_requests_of_stopped_server() {
+        let mut session = Session::default();
+        session.sent_to_server(&Message::request(json!(1), "textDocument/hover", json!({})));
+        session.sent_to_server(&Message::request(json!(2), "textDocument/hover", json!({})));
+        session.received_from_server(&Message::response(json!(1), Value::Null));
+        session.sent_to_client(&Message::request(
+            json!(7),
+            "workspace/configuration",
+            json!({}),
+        ));
+
+        let errors = session.server_stopped();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].id(), Some(&json!(2)));
+        assert!(!session.received_from_client(&Message::response(json!(7), json!([]))));
+        assert!(session.received_from_client(&Message::response(json!(7), json!([]))));
+    }
+}
@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Settings the server asks the client for with `workspace/configuration`.
+///
+/// Groups and options are named like the sections the server asks for, e.g.
+/// `csharp|inlay_hints.csharp_enable_inlay_hints_for_types` is
+/// `inlay_hints.csharp_enable_inlay_hints_for_types`. Options that are not set are left to the
+/// defaults of the server.
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "is_default")]
+    pub inlay_hints: InlayHints,
+    #[serde(skip_serializing_if = "is_default")]
+    pub code_style: CodeStyle,
+    #[serde(skip_serializing_if = "is_default")]
+    pub background_analysis: BackgroundAnalysis,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InlayHints {
+    pub dotnet_enable_inlay_hints_for_parameters: Option<bool>,
+    pub dotnet_enable_inlay_hints_for_literal_parameters: Option<bool>,
+    pub dotnet_enable_inlay_hints_for_indexer_parameters: Option<bool>,
+    pub dotnet_enable_inlay_hints_for_object_creation_parameters: Option<bool>,
+    pub dotnet_enable_inlay_hints_for_other_parameters: Option<bool>,
+    pub dotnet_suppress_inlay_hints_for_parameters_that_differ_only_by_suffix: Option<bool>,
+    pub dotnet_suppress_inlay_hints_for_parameters_that_match_method_intent: Option<bool>,
+    pub dotnet_suppress_inlay_hints_for_parameters_that_match_argument_name: Option<bool>,
+    pub csharp_enable_inlay_hints_for_types: Option<bool>,
+    pub csharp_enable_inlay_hints_for_implicit_variable_types: Option<bool>,
+    pub csharp_enable_inlay_hints_for_lambda_parameter_types: Option<bool>,
+    pub csharp_enable_inlay_hints_for_implicit_object_creation: Option<bool>,
+    pub csharp_enable_inlay_hints_for_collection_expressions: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CodeStyle {
+    #[serde(skip_serializing_if = "is_default")]
+    pub formatting: Formatting,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Formatting {
+    #[serde(skip_serializing_if = "is_default")]
+    pub indentation_and_spacing: IndentationAndSpacing,
+    #[serde(skip_serializing_if = "is_default")]
+    pub new_line: NewLine,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndentationAndSpacing {
+    pub tab_width: Option<u32>,
+    pub indent_size: Option<u32>,
+    pub indent_style: Option<IndentStyle>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IndentStyle {
+    Space,
+    Tab,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NewLine {
+    pub end_of_line: Option<EndOfLine>,
+    pub insert_final_newline: Option<bool>,
+}
+
+/// Line endings. `auto` keeps the line endings of each document.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EndOfLine {
+    Lf,
+    Crlf,
+    Auto,
+}
+
+impl EndOfLine {
+    /// The server expects the characters rather than their name.
+    fn characters(self) -> &'static str {
+        match self {
+            EndOfLine::Lf => "\n",
+            EndOfLine::Crlf => "\r\n",
+            EndOfLine::Auto => "auto",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackgroundAnalysis {
+    pub dotnet_analyzer_diagnostics_scope: Option<DiagnosticsScope>,
+    pub dotnet_compiler_diagnostics_scope: Option<DiagnosticsScope>,
+}
+
+/// Which documents diagnostics are computed for in the background.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum DiagnosticsScope {
+    Default,
+    None,
+    OpenFiles,
+    FullSolution,
+}
+
+impl Settings {
+    /// The value of a `workspace/configuration` section, e.g.
+    /// `csharp|code_style.formatting.indentation_and_spacing.tab_width`. The language before `|`
+    /// is ignored.
+    pub fn section(&self, section: &str) -> Option<Value> {
+        let name = section.split_once('|').map_or(section, |(_, name)| name);
+        if name == "code_style.formatting.new_line.end_of_line" {
+            let end_of_line = self.code_style.formatting.new_line.end_of_line?;
+            return Some(Value::from(end_of_line.characters()));
+        }
+        let settings = serde_json::to_value(self).ok()?;
+        let value = name
+            .split('.')
+            .try_fold(&settings, |value, key| value.get(key))?;
+        (!value.is_null() && !value.is_object()).then(|| value.clone())
+    }
+
+    /// Returns settings where every option set in `other` replaces the one in `self`.
+    pub fn merge(self, other: Settings) -> Settings {
+        let (Ok(mut merged), Ok(other)) =
+            (serde_json::to_value(&self), serde_json::to_value(other))
+        else {
+            return self;
+        };
+        merge_values(&mut merged, other);
+        serde_json::from_value(merged).unwrap_or(self)
+    }
+}
+
+pub(crate) fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn merge_values(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Object(base), Value::Object(other)) => {
+            for (key, value) in other {
+                merge_values(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (_, Value::Null) => {}
+        (base, other) => *base = other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn answers_sections_from_settings() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [inlay_hints]
+            csharp_enable_inlay_hints_for_types = true
+
+            [code_style.formatting.indentation_and_spacing]
+            tab_width = 2
+            indent_style = "tab"
+
+            [code_style.formatting.new_line]
+            end_of_line = "crlf"
+
+            [background_analysis]
+            dotnet_analyzer_diagnostics_scope = "fullSolution"
+            "#,
+        )
+        .unwrap();
+
+        let section = |name| settings.section(name);
+        assert_eq!(
+            section("csharp|inlay_hints.csharp_enable_inlay_hints_for_types"),
+            Some(json!(true))
+        );
+        assert_eq!(
+            section("csharp|code_style.formatting.indentation_and_spacing.tab_width"),
+            Some(json!(2))
+        );
+        assert_eq!(
+            section("visual_basic|code_style.formatting.indentation_and_spacing.indent_style"),
+            Some(json!("tab"))
+        );
+        assert_eq!(
+            section("csharp|code_style.formatting.new_line.end_of_line"),
+            Some(json!("\r\n"))
+        );
+        assert_eq!(
+            section("csharp|background_analysis.dotnet_analyzer_diagnostics_scope"),
+            Some(json!("fullSolution"))
+        );
+        assert_eq!(
+            section("csharp|inlay_hints.dotnet_enable_inlay_hints_for_parameters"),
+            None
+        );
+        assert_eq!(section("csharp|code_style.formatting"), None);
+        assert_eq!(
+            section("navigation.dotnet_navigate_to_decompiled_sources"),
+            None
+        );
+    }
+
+    #[test]
+    fn merges_options_that_are_set() {
+        let mut user = Settings::default();
+        user.inlay_hints.csharp_enable_inlay_hints_for_types = Some(true);
+        user.code_style.formatting.indentation_and_spacing.tab_width = Some(4);
+        let mut workspace = Settings::default();
+        workspace
+            .code_style
+            .formatting
+            .indentation_and_spacing
+            .tab_width = Some(2);
+
+        let merged = user.merge(workspace);
+
+        assert_eq!(
+            merged.inlay_hints.csharp_enable_inlay_hints_for_types,
+            Some(true)
+        );
+        assert_eq!(
+            merged
+                .code_style
+                .formatting
+                .indentation_and_spacing
+                .tab_width,
+            Some(2)
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+/// How to pick a solution when the workspace contains more than one.
+#[derive(ValueEnum, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SolutionSelection {
+    /// The shallowest solution, ordered by name
+    #[default]
+    First,
+    /// The solution containing the first opened document
+    Document,
+    /// Ask the user with `window/showMessageRequest`
+    Ask,
+}
+
+/// The solution with the given file name or file stem.
+pub fn find_named<'a>(candidates: &'a [PathBuf], name: &str) -> Option<&'a PathBuf> {
+    candidates.iter().find(|candidate| {
+        candidate.file_name().is_some_and(|n| n == name)
+            || candidate.file_stem().is_some_and(|n| n == name)
+    })
+}
+
+/// The solution to open without asking or waiting for a document: the only one, the one named
+/// `preferred`, or the one remembered for the workspace root.
+pub fn preselect(
+    candidates: &[PathBuf],
+    preferred: Option<&str>,
+    memory: &SolutionMemory,
+    root_path: &Path,
+) -> Option<PathBuf> {
+    match candidates {
+        [only] => Some(only.clone()),
+        _ => preferred
+            .and_then(|name| find_named(candidates, name))
+            .cloned()
+            .or_else(|| memory.get(root_path)),
+    }
+}
+
+/// The solution with a project containing the document.
+///
+/// Falls back to the solution in the closest parent directory of the document.
+pub fn find_containing<'a>(candidates: &'a [PathBuf], document: &Path) -> Option<&'a PathBuf> {
+    let in_project = candidates.iter().find(|candidate| {
+        project_directories(candidate)
+            .iter()
+            .any(|project_dir| document.starts_with(project_dir))
+    });
+
+    in_project.or_else(|| {
+        candidates
+            .iter()
+            .filter(|candidate| {
+                candidate
+                    .parent()
+                    .is_some_and(|dir| document.starts_with(dir))
+            })
+            .max_by_key(|candidate| candidate.components().count())
+    })
+}
+
+/// Directories of the projects referenced by a `.sln` or `.slnx` file.
+fn project_directories(solution: &Path) -> Vec<PathBuf> {
+    let Ok(content) = fs::read_to_string(solution) else {
+        return vec![];
+    };
+    let Some(solution_dir) = solution.parent() else {
+        return vec![];
+    };
+
+    let project_paths = match solution.extension().and_then(|e| e.to_str()) {
+        Some("slnx") => slnx_project_paths(&content),
+        _ => sln_project_paths(&content),
+    };
+
+    project_paths
+        .into_iter()
+        .filter_map(|project| {
+            let project = solution_dir.join(project.replace('\\', "/"));
+            project.parent().map(Path::to_path_buf)
+        })
+        .collect()
+}
+
+/// `Project("{type}") = "Name", "path/to/Name.csproj", "{guid}"`
+fn sln_project_paths(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter(|line| line.trim_start().starts_with("Project("))
+        .filter_map(|line| line.split(',').nth(1))
+        .map(|path| path.trim().trim_matches('"').to_string())
+        // Solution folders are listed as projects without a project file
+        .filter(|path| path.ends_with("proj"))
+        .collect()
+}
+
+/// `<Project Path="path/to/Name.csproj" />`
+fn slnx_project_paths(content: &str) -> Vec<String> {
+    content
+        .split("<Project ")
+        .skip(1)
+        .filter_map(|element| {
+            let start = element.find("Path=\"")? + "Path=\"".len();
+            let end = element[start..].find('"')?;
+            Some(element[start..start + end].to_string())
+        })
+        .collect()
+}
+
+/// Solutions previously picked for each workspace root.
+#[derive(Clone)]
+pub struct SolutionMemory {
+    file: PathBuf,
+}
+
+impl Default for SolutionMemory {
+    fn default() -> Self {
+        let data_dir = match ProjectDirs::from("com", "github", "csharp-language-server") {
+            Some(dirs) => dirs.data_local_dir().to_path_buf(),
+            None => std::env::temp_dir().join("csharp-language-server"),
+        };
+
+        Self::at(data_dir.join("solutions.json"))
+    }
+}
+
+impl SolutionMemory {
+    pub fn at(file: PathBuf) -> Self {
+        Self { file }
+    }
+
+    /// The remembered solution of a workspace root, if it still exists.
+    pub fn get(&self, root_path: &Path) -> Option<PathBuf> {
+        self.read()
+            .remove(root_path)
+            .filter(|solution| solution.exists())
+    }
+
+    pub fn remember(&self, root_path: &Path, solution: &Path) -> Result<()> {
+        let mut solutions = self.read();
+        solutions.insert(root_path.to_path_buf(), solution.to_path_buf());
+
+        if let Some(parent) = self.file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.file, serde_json::to_string_pretty(&solutions)?)?;
+        Ok(())
+    }
+
+    fn read(&self) -> HashMap<PathBuf, PathBuf> {
+        fs::read_to_string(&self.file)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const SLN: &str = r#"
+Microsoft Visual Studio Solution File, Format Version 12.00
+Project("{FAE04EC0-301F-11D3-BF4B-00C04F79EFBC}") = "Api", "src\Api\Api.csproj", "{0A1B}"
+EndProject
+Project("{2150E333-8FDC-42A3-9474-1A3956D46DE8}") = "tests", "tests", "{0C1D}"
+EndProject
+"#;
+
+    const SLNX: &str = r#"
+<Solution>
+  <Folder Name="/src/">
+    <Project Path="src/Worker/Worker.csproj" />
+  </Folder>
+</Solution>
+"#;
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn parses_project_paths() {
+        assert_eq!(sln_project_paths(SLN), vec![r"src\Api\Api.csproj"]);
+        assert_eq!(slnx_project_paths(SLNX), vec!["src/Worker/Worker.csproj"]);
+    }
+
+    #[test]
+    fn finds_solution_containing_document() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path();
+        let api = root.join("Api.sln");
+        let worker = root.join("Worker.slnx");
+        write(&api, SLN);
+        write(&worker, SLNX);
+        let candidates = vec![api.clone(), worker.clone()];
+
+        let document = root.join("src").join("Worker").join("Program.cs");
+        assert_eq!(find_containing(&candidates, &document), Some(&worker));
+
+        let document = root.join("src").join("Api").join("Program.cs");
+        assert_eq!(find_containing(&candidates, &document), Some(&api));
+    }
+
+    #[test]
+    fn falls_back_to_closest_solution_directory() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path();
+        let outer = root.join("All.sln");
+        let inner = root.join("tools").join("Tools.sln");
+        write(&outer, "");
+        write(&inner, "");
+        let candidates = vec![outer, inner.clone()];
+
+        let document = root.join("tools").join("Cli").join("Program.cs");
+        assert_eq!(find_containing(&candidates, &document), Some(&inner));
+    }
+
+    #[test]
+    fn finds_named_solution() {
+        let candidates = vec![PathBuf::from("/a/Api.sln"), PathBuf::from("/b/Worker.slnx")];
+
+        assert_eq!(find_named(&candidates, "Worker"), Some(&candidates[1]));
+        assert_eq!(find_named(&candidates, "Api.sln"), Some(&candidates[0]));
+        assert_eq!(find_named(&candidates, "Other"), None);
+    }
+
+    #[test]
+    fn remembers_solution_per_root() {
+        let tmp = TempDir::new().unwrap();
+        let memory = SolutionMemory::at(tmp.path().join("state").join("solutions.json"));
+        let solution = tmp.path().join("Api.sln");
+        write(&solution, "");
+
+        assert_eq!(memory.get(tmp.path()), None);
+
+        memory.remember(tmp.path(), &solution).unwrap();
+        assert_eq!(memory.get(tmp.path()), Some(solution.clone()));
+
+        fs::remove_file(&solution).unwrap();
+        assert_eq!(memory.get(tmp.path()), None);
+    }
+}
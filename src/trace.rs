@@ -0,0 +1,121 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, LineWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::transport::Message;
+
+/// Where a message was going when it passed through the proxy.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    FromClient,
+    ToServer,
+    FromServer,
+    ToClient,
+}
+
+/// A line of a trace file.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Entry {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub direction: Direction,
+    /// The message, or its body as a string when it is not JSON
+    pub message: Value,
+}
+
+/// Records the messages passing through the proxy as JSON lines. The default trace records
+/// nothing.
+#[derive(Clone, Default)]
+pub struct Trace {
+    file: Option<Arc<Mutex<LineWriter<File>>>>,
+}
+
+impl Trace {
+    /// Records to `path`, replacing a previous trace.
+    pub fn create(path: &Path) -> Result<Trace> {
+        let file = File::create(path)
+            .with_context(|| format!("Unable to create trace file {}", path.display()))?;
+        Ok(Trace {
+            file: Some(Arc::new(Mutex::new(LineWriter::new(file)))),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, message: &Message) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let entry = Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            direction,
+            message: message.json().cloned().unwrap_or_else(|| {
+                Value::String(String::from_utf8_lossy(message.body()).to_string())
+            }),
+        };
+        let mut line = serde_json::to_string(&entry).expect("Unable to serialize trace entry");
+        line.push('\n');
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Unable to write to the trace file: {e}");
+        }
+    }
+}
+
+/// Reads the entries of a trace file.
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let file = File::open(path)
+        .with_context(|| format!("Unable to open trace file {}", path.display()))?;
+    let mut entries = vec![];
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .with_context(|| format!("{}:{} is not a trace entry", path.display(), index + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    #[test]
+    fn records_messages_as_json_lines() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("trace.jsonl");
+        let trace = Trace::create(&path).unwrap();
+
+        trace.record(
+            Direction::FromClient,
+            &Message::request(json!(1), "shutdown", Value::Null),
+        );
+        trace.record(
+            Direction::FromServer,
+            &Message::from_body(b"{oops".to_vec()),
+        );
+        drop(trace);
+
+        let entries = read(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].direction, Direction::FromClient);
+        assert_eq!(entries[0].message["method"], "shutdown");
+        assert_eq!(entries[1].message, "{oops");
+        assert!(entries[0].timestamp > 0);
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// A single JSON-RPC message as framed on the wire.
+///
+/// The body is kept as the exact bytes that were received, so a message that is only inspected
+/// is forwarded unchanged.
+#[derive(Debug, Clone)]
+pub struct Message {
+    body: Vec<u8>,
+    json: Option<Value>,
+}
+
+impl Message {
+    pub fn from_body(body: Vec<u8>) -> Self {
+        let json = serde_json::from_slice(&body).ok();
+        Self { body, json }
+    }
+
+    pub fn from_value(json: Value) -> Self {
+        let body = serde_json::to_vec(&json).expect("Unable to serialize message");
+        Self {
+            body,
+            json: Some(json),
+        }
+    }
+
+    pub fn request(id: Value, method: &str, params: Value) -> Self {
+        Self::from_value(json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))
+    }
+
+    pub fn notification(method: &str, params: Value) -> Self {
+        Self::from_value(json!({"jsonrpc": "2.0", "method": method, "params": params}))
+    }
+
+    pub fn response(id: Value, result: Value) -> Self {
+        Self::from_value(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+    }
+
+    pub fn error_response(id: Value, code: i64, message: &str) -> Self {
+        Self::from_value(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": code, "message": message}
+        }))
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn json(&self) -> Option<&Value> {
+        self.json.as_ref()
+    }
+
+    pub fn method(&self) -> Option<&str> {
+        self.json.as_ref()?.get("method")?.as_str()
+    }
+
+    pub fn id(&self) -> Option<&Value> {
+        self.json.as_ref()?.get("id")
+    }
+
+    pub fn params(&self) -> Option<&Value> {
+        self.json.as_ref()?.get("params")
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.method().is_some() && self.id().is_some()
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.method().is_none() && self.id().is_some()
+    }
+}
+
+/// The longest header line accepted, well above any `Content-Length` or `Content-Type` header.
+const MAX_HEADER_LINE: u64 = 1024;
+
+/// The largest body accepted. Larger announced lengths are treated as broken framing rather than
+/// allocated.
+const MAX_CONTENT_LENGTH: usize = 256 * 1024 * 1024;
+
+pub struct MessageReader<R> {
+    reader: R,
+}
+
+impl<R: AsyncBufRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    /// The underlying reader, with any input after the last message read.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the next message. Returns `None` when the stream ends between messages.
+    pub async fn read(&mut self) -> Result<Option<Message>> {
+        let mut content_length = None;
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let bytes_read = (&mut self.reader)
+                .take(MAX_HEADER_LINE)
+                .read_line(&mut line)
+                .await?;
+            if bytes_read as u64 == MAX_HEADER_LINE && !line.ends_with('\n') {
+                bail!("Message header line is longer than {MAX_HEADER_LINE} bytes");
+            }
+            if bytes_read == 0 {
+                if content_length.is_none() {
+                    return Ok(None);
+                }
+                bail!("Stream ended inside message header");
+            }
+
+            let header = line.trim_end_matches(['\r', '\n']);
+            if header.is_empty() {
+                if content_length.is_some() {
+                    break;
+                }
+                // Tolerate stray blank lines between messages
+                continue;
+            }
+
+            let (name, value) = header
+                .split_once(':')
+                .with_context(|| format!("Malformed message header: {header:?}"))?;
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                let length = value
+                    .trim()
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid Content-Length: {value:?}"))?;
+                if length > MAX_CONTENT_LENGTH {
+                    bail!("Content-Length {length} is larger than {MAX_CONTENT_LENGTH} bytes");
+                }
+                content_length = Some(length);
+            }
+        }
+
+        // Read rather than allocate up front, so a stream ending early never allocates the whole
+        // announced length
+        let content_length = content_length.unwrap_or_default();
+        let mut body = Vec::with_capacity(content_length.min(64 * 1024));
+        (&mut self.reader)
+            .take(content_length as u64)
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() < content_length {
+            bail!("Stream ended inside message body");
+        }
+
+        Ok(Some(Message::from_body(body)))
+    }
+}
+
+pub struct MessageWriter<W> {
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub async fn write(&mut self, message: &Message) -> Result<()> {
+        let header = format!("Content-Length: {}\r\n\r\n", message.body.len());
+        self.writer.write_all(header.as_bytes()).await?;
+        self.writer.write_all(&message.body).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::BufReader;
+
+    async fn read_all(input: &[u8]) -> Result<Vec<Message>> {
+        let mut reader = MessageReader::new(BufReader::new(input));
+        let mut messages = vec![];
+        while let Some(message) = reader.read().await? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    #[tokio::test]
+    async fn reads_consecutive_messages() {
+        let input = b"Content-Length: 17\r\n\r\n{\"method\":\"exit\"}\
+Content-Length: 2\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}";
+
+        let messages = read_all(input).await.unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].method(), Some("exit"));
+        assert_eq!(messages[1].body(), b"{}");
+    }
+
+    #[tokio::test]
+    async fn keeps_body_bytes_unchanged() {
+        let body = "{ \"method\" : \"initialize\",\n \"params\": {\"text\": \"æøå\"} }";
+        let input = format!("content-length: {}\r\n\r\n{body}", body.len());
+
+        let messages = read_all(input.as_bytes()).await.unwrap();
+
+        assert_eq!(messages[0].body(), body.as_bytes());
+        assert_eq!(messages[0].method(), Some("initialize"));
+    }
+
+    #[tokio::test]
+    async fn keeps_non_json_body() {
+        let input = b"Content-Length: 3\r\n\r\n\xff\xfe\xfd";
+
+        let messages = read_all(input).await.unwrap();
+
+        assert_eq!(messages[0].body(), b"\xff\xfe\xfd");
+        assert!(messages[0].json().is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_on_truncated_body() {
+        let input = b"Content-Length: 10\r\n\r\n{}";
+
+        assert!(read_all(input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_framing() {
+        let input = format!("Content-Length: {}\r\n\r\n{{}}", MAX_CONTENT_LENGTH + 1);
+        assert!(read_all(input.as_bytes()).await.is_err());
+
+        let input = format!(
+            "X-Padding: {}\r\nContent-Length: 2\r\n\r\n{{}}",
+            "a".repeat(4096)
+        );
+        assert!(read_all(input.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_framed_message() {
+        let mut output = vec![];
+        let message = Message::from_value(json!({"jsonrpc": "2.0", "method": "exit"}));
+
+        MessageWriter::new(&mut output)
+            .write(&message)
+            .await
+            .unwrap();
+
+        let messages = read_all(&output).await.unwrap();
+        assert_eq!(messages[0].body(), message.body());
+    }
+}